@@ -139,6 +139,14 @@ impl Daemon {
                         continue;
                     }
 
+                    // nobody reads a reply to the kernel's notices
+                    if let MessageType::Stalled = message.message_type {
+                        if let Err(e) = (self.callback)(self, message) {
+                            self.log(format_args!("Error handling notice: {}", e));
+                        }
+                        continue;
+                    }
+
                     self.log(format_args!("Handling message: {:?}", message));
                     match (self.callback)(self, message) {
                         Ok(n) => {
@@ -15,6 +15,8 @@ pub enum MessageType {
     Write,
     Delete,
     Heartbeat,
+    /// Sent by the kernel's watchdog with the PID of a task stuck running, expects no reply
+    Stalled,
 }
 
 impl Message {
@@ -19,3 +19,13 @@ pub fn lseek(fd: usize, offset: usize, whence: usize) -> Result<usize, i32> {
 pub fn close(fd: usize) -> Result<(), i32> {
     syscall(57, fd, 0, 0, 0, 0, 0).map(|_| ())
 }
+
+/// Maps the first `size` bytes of what `fd` has open, returns where the mapping starts
+pub fn mmap(fd: usize, size: usize) -> Result<usize, i32> {
+    syscall(224, fd, size, 0, 0, 0, 0)
+}
+
+/// Removes a mapping made by `mmap`
+pub fn munmap(address: usize) -> Result<(), i32> {
+    syscall(215, address, 0, 0, 0, 0, 0).map(|_| ())
+}
@@ -0,0 +1,5 @@
+use crate::syscall;
+
+pub fn getrandom(buf: &mut [u8]) -> Result<usize, i32> {
+    syscall(278, buf.as_mut_ptr() as usize, buf.len(), 0, 0, 0, 0)
+}
@@ -12,6 +12,41 @@ pub fn kill(pid: usize) -> Result<usize, i32> {
     syscall(62, pid, 0, 0, 0, 0, 0)
 }
 
+/// Kills every task in the process group but the caller, returns how many were killed
+pub fn kill_group(pgid: usize) -> Result<usize, i32> {
+    syscall(62, (pgid as isize).wrapping_neg() as usize, 0, 0, 0, 0, 0)
+}
+
+/// Moves `pid` into the group `pgid`, 0 for either means the caller
+pub fn setpgid(pid: usize, pgid: usize) -> Result<usize, i32> {
+    syscall(154, pid, pgid, 0, 0, 0, 0)
+}
+
+pub fn getpgid(pid: usize) -> Result<usize, i32> {
+    syscall(155, pid, 0, 0, 0, 0, 0)
+}
+
+/// Starts a new session led by the caller, returns its id
+pub fn setsid() -> Result<usize, i32> {
+    syscall(157, 0, 0, 0, 0, 0, 0)
+}
+
 pub fn spawn(binary_index: usize) -> Result<usize, i32> {
     syscall(220, binary_index, 0, 0, 0, 0, 0)
 }
+
+/// Duplicates the caller, returns the child's PID in the parent and 0 in the child
+pub fn fork() -> Result<usize, i32> {
+    syscall(223, 0, 0, 0, 0, 0, 0)
+}
+
+/// Asks for `runtime` ms of CPU every `period` ms, finished within `deadline` ms of the
+/// period starting, fails with EBUSY when the CPU can't guarantee it
+pub fn sched_setattr(period: usize, runtime: usize, deadline: usize) -> Result<usize, i32> {
+    syscall(314, period, runtime, deadline, 0, 0, 0)
+}
+
+/// Blocks for at least `ms` milliseconds
+pub fn sleep(ms: usize) -> Result<usize, i32> {
+    syscall(101, ms, 0, 0, 0, 0, 0)
+}
@@ -3,7 +3,7 @@
 
 mod shift;
 
-use jon_common::{daemon::Daemon, ipc::Message};
+use jon_common::{daemon::Daemon, ipc::Message, syscall::random::getrandom};
 use shift::XorShift64;
 use spinning_top::Spinlock;
 
@@ -12,6 +12,7 @@ static RNG: Spinlock<XorShift64> = Spinlock::new(XorShift64::new());
 #[unsafe(no_mangle)]
 pub extern "C" fn _start() -> ! {
     let daemon = Daemon::new(main);
+    seed(&daemon);
     daemon.register("random").unwrap();
     daemon.start();
 }
@@ -21,3 +22,12 @@ fn main(_daemon: &Daemon, message: Message) -> Result<usize, i32> {
         _ => Ok(RNG.lock().next_u64() as usize),
     }
 }
+
+fn seed(daemon: &Daemon) {
+    let mut buf = [0u8; 8];
+
+    match getrandom(&mut buf) {
+        Ok(_) => RNG.lock().seed(u64::from_ne_bytes(buf)),
+        Err(e) => daemon.log(format_args!("Failed to seed from kernel entropy: {}", e)),
+    }
+}
@@ -9,6 +9,13 @@ impl XorShift64 {
         }
     }
 
+    /// Reseeds the generator, a zero seed is ignored as it would get xorshift stuck at zero
+    pub fn seed(&mut self, seed: u64) {
+        if seed != 0 {
+            self.state = seed;
+        }
+    }
+
     pub fn next_u64(&mut self) -> u64 {
         let mut x = self.state;
         x ^= x >> 12;
@@ -7,10 +7,17 @@ extern crate alloc;
 use alloc::{collections::btree_map::BTreeMap, string::String, vec::Vec};
 use allocator::init;
 use core::ffi::CStr;
-use jon_common::{ExitCode, daemon::Daemon, ipc::Message};
+use jon_common::{
+    ExitCode,
+    daemon::Daemon,
+    ipc::Message,
+    syscall::task::{kill, spawn},
+};
 use spinning_top::Spinlock;
 
 static NAMES: Spinlock<BTreeMap<String, Vec<usize>>> = Spinlock::new(BTreeMap::new());
+/// Daemons that can be started again, with the binary index `spawn` takes
+const RESTARTABLE: [(&str, usize); 2] = [("random", 2), ("random-echo", 3)];
 
 #[unsafe(no_mangle)]
 pub extern "C" fn _start() -> ! {
@@ -104,6 +111,41 @@ fn main(daemon: &Daemon, message: Message) -> Result<usize, i32> {
 
             Ok(0)
         }
+        // the kernel's watchdog caught the task hogging its CPU
+        jon_common::ipc::MessageType::Stalled => {
+            let mut pid_buf = [0u8; 8];
+            pid_buf.copy_from_slice(&message.data[..8]);
+            let pid = usize::from_ne_bytes(pid_buf);
+            daemon.log(format_args!(
+                "Daemon with pid {} is stuck, restarting it",
+                pid
+            ));
+            kill(pid)?;
+
+            let name = NAMES.lock().iter_mut().find_map(|(name, pids)| {
+                let pos = pids.iter().position(|&x| x == pid)?;
+                pids.remove(pos);
+
+                Some(name.clone())
+            });
+            let Some(name) = name else {
+                daemon.log(format_args!("Pid {} is not a registered daemon", pid));
+                return Ok(0);
+            };
+
+            match RESTARTABLE.iter().find(|(binary, _)| *binary == name) {
+                Some((_, index)) => {
+                    let new_pid = spawn(*index)?;
+                    daemon.log(format_args!("Restarted daemon {} as pid {}", name, new_pid));
+
+                    Ok(new_pid)
+                }
+                None => {
+                    daemon.log(format_args!("Daemon {} can't be restarted", name));
+                    Ok(0)
+                }
+            }
+        }
         // heartbeats are handled by the daemon upstream
         jon_common::ipc::MessageType::Heartbeat => unreachable!(),
     }
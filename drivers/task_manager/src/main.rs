@@ -8,13 +8,14 @@ use writer::FramebufferWriter;
 
 mod allocator;
 mod proc;
+mod sys;
 mod ui;
 mod writer;
 
 extern crate alloc;
 
 static SERIAL_FD: Spinlock<usize> = Spinlock::new(0);
-pub const Y_OFFSET: usize = FONT_SIZE.val() * 2 + 8;
+pub const Y_OFFSET: usize = FONT_SIZE.val() * 3 + 16;
 
 #[unsafe(no_mangle)]
 pub extern "C" fn _start() -> ! {
@@ -1,9 +1,9 @@
-use alloc::{format, vec::Vec};
+use alloc::{format, vec, vec::Vec};
 use jon_common::{
     ipc::Message,
     syscall::{
         fs::{open, read, write},
-        task::kill,
+        task::{kill, kill_group},
     },
 };
 
@@ -13,15 +13,41 @@ use crate::{SERIAL_FD, log};
 #[derive(Debug)]
 pub struct Proc {
     pub pid: usize,
+    /// 0 for tasks started by the kernel
+    pub ppid: usize,
+    pub pgid: usize,
+    pub sid: usize,
     pub name: [u8; 16],
     pub state: State,
     pub priority: Priority,
+    pub last_cpu: u64,
+    /// Times are in milliseconds
+    pub user_ms: u64,
+    pub system_ms: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    pub start_ms: u64,
 }
 
 impl Proc {
     pub fn from_bytes(bytes: &[u8]) -> Self {
         unsafe { core::ptr::read(bytes.as_ptr() as *const Proc) }
     }
+
+    /// Time the task has been on a CPU, in user mode or in the kernel
+    pub fn cpu_ms(&self) -> u64 {
+        self.user_ms + self.system_ms
+    }
+}
+
+/// Task table and reaper counters from proc:stats
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct ProcStats {
+    pub tasks: usize,
+    pub zombies: usize,
+    pub reaped: u64,
+    pub freed_frames: u64,
 }
 
 #[repr(u8)]
@@ -42,7 +68,8 @@ pub enum State {
 }
 
 pub fn list_procs(proc_fd: usize) -> Vec<Proc> {
-    let mut buf = [0u8; 128 * size_of::<Proc>()];
+    // too big for the stack
+    let mut buf = vec![0u8; 128 * size_of::<Proc>()];
     let bytes_read = read(proc_fd, &mut buf).unwrap();
     let procs_buf = &buf[..bytes_read];
 
@@ -56,6 +83,17 @@ pub fn list_procs(proc_fd: usize) -> Vec<Proc> {
     procs
 }
 
+pub fn proc_stats(stats_fd: usize) -> ProcStats {
+    let mut buf = [0u8; size_of::<ProcStats>()];
+
+    match read(stats_fd, &mut buf) {
+        Ok(bytes_read) if bytes_read == buf.len() => unsafe {
+            core::ptr::read(buf.as_ptr() as *const ProcStats)
+        },
+        _ => ProcStats::default(),
+    }
+}
+
 pub fn kill_proc(proc: &Proc) {
     if proc.state != State::Running && proc.state != State::Waiting {
         write(*SERIAL_FD.lock(), b"Task not running, cannot kill").unwrap();
@@ -82,11 +120,43 @@ pub fn kill_proc(proc: &Proc) {
         }
     }
 
+    unregister(proc.pid);
+}
+
+/// Kills every task in the selected task's process group
+pub fn kill_proc_group(proc: &Proc, procs: &[Proc]) {
+    log("Attempting to kill task group...");
+
+    match kill_group(proc.pgid) {
+        Ok(killed) => {
+            write(
+                *SERIAL_FD.lock(),
+                format!("Tasks killed: {}", killed).as_bytes(),
+            )
+            .unwrap();
+        }
+        Err(e) => {
+            write(
+                *SERIAL_FD.lock(),
+                format!("Error killing task group: {}", e).as_bytes(),
+            )
+            .unwrap();
+            return;
+        }
+    }
+
+    for member in procs.iter().filter(|member| member.pgid == proc.pgid) {
+        unregister(member.pid);
+    }
+}
+
+/// Tells reincarnation the task is gone, so it isn't handed out as a daemon anymore
+fn unregister(pid: usize) {
     log("Sending kill message...");
     let fd = open("pipe:1/read", 0x2).unwrap();
     log("Writing to pipe...");
     let mut pid_buf = [0u8; 16];
-    pid_buf[..8].copy_from_slice(&proc.pid.to_ne_bytes());
+    pid_buf[..8].copy_from_slice(&pid.to_ne_bytes());
     write(
         fd,
         Message::new(jon_common::ipc::MessageType::Delete, pid_buf).to_bytes(),
@@ -0,0 +1,66 @@
+use alloc::{format, string::String};
+use jon_common::syscall::fs::{lseek, open, read};
+
+/// Snapshot of the kernel's `sys:` entries shown in the header
+pub struct SystemInfo {
+    version: String,
+    cpus_fd: usize,
+    uptime_fd: usize,
+    meminfo_fd: usize,
+}
+
+impl SystemInfo {
+    pub fn new() -> Self {
+        let version_fd = open("sys:version", 0x1).unwrap();
+        let version = read_entry(version_fd);
+        // "JonOS 0.1.0 (x86_64)" -> "JonOS 0.1.0"
+        let version = match version.split_once(" (") {
+            Some((name, _)) => String::from(name),
+            None => String::from(version.trim()),
+        };
+
+        Self {
+            version,
+            cpus_fd: open("sys:cpus", 0x1).unwrap(),
+            uptime_fd: open("sys:uptime", 0x1).unwrap(),
+            meminfo_fd: open("sys:meminfo", 0x1).unwrap(),
+        }
+    }
+
+    /// Milliseconds since boot
+    pub fn uptime(&self) -> u64 {
+        read_entry(self.uptime_fd).trim().parse().unwrap_or(0)
+    }
+
+    pub fn summary(&self) -> String {
+        let cpus = read_entry(self.cpus_fd).lines().count();
+        let uptime = self.uptime() / 1000;
+        let meminfo = read_entry(self.meminfo_fd);
+        let total = meminfo_field(&meminfo, "total") / 1024;
+        let used = meminfo_field(&meminfo, "used") / 1024;
+
+        format!(
+            "{} | {} CPUs | up {}s | {}/{} MiB",
+            self.version, cpus, uptime, used, total
+        )
+    }
+}
+
+fn read_entry(fd: usize) -> String {
+    let mut buf = [0u8; 512];
+    lseek(fd, 0, 0).unwrap();
+    let bytes_read = read(fd, &mut buf).unwrap_or(0);
+
+    String::from_utf8_lossy(&buf[..bytes_read]).into_owned()
+}
+
+/// Parses a "name: N KiB" line from sys:meminfo
+fn meminfo_field(meminfo: &str, name: &str) -> usize {
+    meminfo
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| value.split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
@@ -1,15 +1,16 @@
 use core::ffi::CStr;
 
-use alloc::{format, vec::Vec};
+use alloc::{collections::btree_map::BTreeMap, format, vec::Vec};
 use jon_common::syscall::{
     fs::{open, read},
-    task::spawn,
+    task::{setpgid, spawn},
 };
 use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet2, layouts};
 
 use crate::{
     Y_OFFSET, log,
-    proc::{Proc, State, kill_proc, list_procs},
+    proc::{Proc, State, kill_proc, kill_proc_group, list_procs, proc_stats},
+    sys::SystemInfo,
     writer::FramebufferWriter,
 };
 
@@ -22,10 +23,15 @@ pub struct Screen {
     pub screen_state: ScreenState,
     writer: FramebufferWriter,
     proc_fd: usize,
+    stats_fd: usize,
     keyboard_fd: usize,
     keyboard: Keyboard<layouts::Us104Key, ScancodeSet2>,
     selected_proc: usize,
     procs: Vec<Proc>,
+    system_info: SystemInfo,
+    /// CPU time of each pid at the previous refresh, used for the CPU% column
+    cpu_samples: BTreeMap<usize, u64>,
+    last_uptime: u64,
 }
 
 pub enum ScreenState {
@@ -46,10 +52,14 @@ impl Screen {
             screen_state: ScreenState::Selection,
             writer,
             proc_fd,
+            stats_fd: open("proc:stats", 0x0).unwrap(),
             keyboard_fd,
             keyboard,
             selected_proc: 0,
             procs: Vec::new(),
+            system_info: SystemInfo::new(),
+            cpu_samples: BTreeMap::new(),
+            last_uptime: 0,
         }
     }
 
@@ -67,7 +77,6 @@ impl Screen {
         }
 
         self.read_keyboard();
-        self.writer.flush();
     }
 
     fn draw_header(&mut self) {
@@ -77,21 +86,55 @@ impl Screen {
             "Task Manager - Use as setas para navegar",
             Color::White,
         );
+        let stats = proc_stats(self.stats_fd);
+        let summary = format!(
+            "{} | {} recolhidas ({} KiB)",
+            self.system_info.summary(),
+            stats.reaped,
+            stats.freed_frames * 4
+        );
+        self.writer
+            .write_text(0, FONT_SIZE.val() + PADDING, &summary, Color::Cyan);
+    }
+
+    /// Share of one CPU each task used since the previous refresh
+    fn cpu_usage(&mut self) -> BTreeMap<usize, u64> {
+        let uptime = self.system_info.uptime();
+        let elapsed = uptime.saturating_sub(self.last_uptime);
+        let mut usage = BTreeMap::new();
+
+        for proc in self.procs.iter() {
+            let cpu_ms = proc.cpu_ms();
+            let previous = self.cpu_samples.insert(proc.pid, cpu_ms).unwrap_or(cpu_ms);
+            let percent = if elapsed == 0 {
+                0
+            } else {
+                (cpu_ms.saturating_sub(previous) * 100 / elapsed).min(100)
+            };
+            usage.insert(proc.pid, percent);
+        }
+
+        self.cpu_samples
+            .retain(|pid, _| self.procs.iter().any(|proc| proc.pid == *pid));
+        self.last_uptime = uptime;
+
+        usage
     }
 
     fn draw_selection(&mut self) {
         self.procs = list_procs(self.proc_fd);
+        let usage = self.cpu_usage();
         self.draw_header();
         self.writer.write_text(
             0,
-            FONT_SIZE.val() + PADDING,
-            "PID NOME             ESTADO",
+            (FONT_SIZE.val() + PADDING) * 2,
+            "PID GRP NOME             ESTADO     CPU%",
             Color::White,
         );
         self.writer.write_text(
             0,
             self.legend_offset(),
-            "K - Matar | N - Novo",
+            "K - Matar | G - Matar grupo | N - Novo",
             Color::White,
         );
 
@@ -113,7 +156,11 @@ impl Screen {
                 .unwrap()
                 .to_str()
                 .unwrap();
-            let text = format!("{:>3} {:<16} {:<10}", proc.pid, name, state_label);
+            let cpu = usage.get(&proc.pid).copied().unwrap_or(0);
+            let text = format!(
+                "{:>3} {:>3} {:<16} {:<10} {:>3}%",
+                proc.pid, proc.pgid, name, state_label, cpu
+            );
             self.writer.write_text(0, row_y, &text, color);
         }
     }
@@ -171,6 +218,10 @@ impl Screen {
                     let proc = &self.procs[self.selected_proc];
                     kill_proc(proc);
                 }
+                DecodedKey::Unicode('g') => {
+                    let proc = &self.procs[self.selected_proc];
+                    kill_proc_group(proc, &self.procs);
+                }
                 DecodedKey::Unicode('n') => {
                     self.writer.force_clear();
                     self.selected_proc = 0;
@@ -195,7 +246,12 @@ impl Screen {
                     self.screen_state = ScreenState::Selection;
                 }
                 DecodedKey::Unicode('s') => match spawn(self.selected_proc + 2) {
-                    Ok(_) => {}
+                    // each new task gets its own group, so it can be killed along with its helpers
+                    Ok(pid) => {
+                        if let Err(e) = setpgid(pid, 0) {
+                            log(&format!("Falha ao criar grupo para {}: {}", pid, e));
+                        }
+                    }
                     Err(e) => log(&format!(
                         "Falha ao criar processo {}: {}",
                         NEW_PROCS[self.selected_proc], e
@@ -1,23 +1,24 @@
-use alloc::vec::Vec;
+use jon_common::syscall::fs::mmap;
 use noto_sans_mono_bitmap::{FontWeight, get_raster};
 
 use crate::ui::{Color, FONT_SIZE, Framebuffer};
 
 pub struct FramebufferWriter {
-    fd: usize,
     framebuffer: Framebuffer,
-    buffer: Vec<u8>,
+    /// The framebuffer itself, mapped from `vga:`
+    buffer: &'static mut [u8],
     dirty_start: (usize, usize),
     dirty_end: (usize, usize),
 }
 
 impl FramebufferWriter {
     pub fn new(fd: usize, framebuffer: Framebuffer) -> Self {
-        let buffer = alloc::vec![0; (framebuffer.height * framebuffer.pitch) as usize];
+        let size = (framebuffer.height * framebuffer.pitch) as usize;
+        let address = mmap(fd, size).unwrap();
+        let buffer = unsafe { core::slice::from_raw_parts_mut(address as *mut u8, size) };
         let width = framebuffer.width as usize;
         let height = framebuffer.height as usize;
         let mut writer = Self {
-            fd,
             framebuffer,
             buffer,
             dirty_start: (0, 0),
@@ -135,8 +136,4 @@ impl FramebufferWriter {
             }
         }
     }
-
-    pub fn flush(&mut self) {
-        jon_common::syscall::fs::write(self.fd, &self.buffer).unwrap();
-    }
 }
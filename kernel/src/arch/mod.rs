@@ -1,4 +1,4 @@
-use x86::{interrupts::current_lapic_mut, structures::Registers};
+use x86::interrupts::current_lapic_mut;
 use x86_64::instructions::interrupts::disable;
 
 use crate::sched::task::Task;
@@ -16,9 +16,9 @@ pub unsafe fn end_of_interrupt() {
     current_lapic_mut().end_of_interrupt();
 }
 
-pub unsafe fn switch_to(prev: Option<&mut Task>, next: &Task, current_stack_frame: &Registers) {
+pub unsafe fn switch_to(prev: Option<&mut Task>, next: &Task) {
     #[cfg(target_arch = "x86_64")]
-    x86::sched::switch_to(prev, next, current_stack_frame);
+    x86::sched::switch_to(prev, next);
 }
 
 pub fn panic(_info: &core::panic::PanicInfo) {
@@ -1,10 +1,14 @@
 use core::{arch::asm, ptr::addr_of};
 
-use alloc::format;
+use alloc::{boxed::Box, format};
 use limine::{request::SmpRequest, smp::Cpu};
 use log::info;
 use x86_64::{
     instructions::interrupts::enable,
+    registers::{
+        control::{Cr0, Cr0Flags, Efer, EferFlags},
+        model_specific::Msr,
+    },
     structures::{
         gdt::GlobalDescriptorTable, idt::InterruptDescriptorTable, tss::TaskStateSegment,
     },
@@ -27,6 +31,10 @@ use super::{gdt::Selectors, sched::SchedulerInfo};
 static SMP_REQUEST: SmpRequest = SmpRequest::new();
 
 pub const MAX_CPUS: usize = 4;
+const IA32_PAT: u32 = 0x277;
+/// Limine's PAT with entry 1 turned from write-through to write-combining, see
+/// `PageFlags::WRITE_COMBINING`
+const PAT: u64 = 0x0007_0105_0007_0106;
 pub static mut PCRS: [ProcessorControlRegion; MAX_CPUS] = [
     ProcessorControlRegion::new(),
     ProcessorControlRegion::new(),
@@ -46,6 +54,7 @@ pub struct ProcessorControlRegion {
     pub idt: InterruptDescriptorTable,
     pub sched: SchedulerInfo,
     pub selectors: Option<Selectors>,
+    pub online: bool,
     idle_task: Option<Pid>,
 }
 
@@ -61,6 +70,7 @@ impl ProcessorControlRegion {
             idt: InterruptDescriptorTable::new(),
             sched: SchedulerInfo::new(),
             selectors: None,
+            online: false,
             idle_task: None,
         }
     }
@@ -69,12 +79,17 @@ impl ProcessorControlRegion {
         if self.idle_task.is_none() {
             let task = Task::idle();
             let pid = task.pid;
-            TASKS.write().insert(pid, task);
+            TASKS.write().insert(pid, Box::new(task));
             self.idle_task = Some(pid);
         }
 
         self.idle_task.unwrap()
     }
+
+    /// Whether the CPU has nothing but its idle task to run right now
+    pub fn running_idle(&self) -> bool {
+        self.sched.current_pid.is_none() || self.sched.current_pid == self.idle_task
+    }
 }
 
 pub fn init() {
@@ -138,10 +153,18 @@ fn init_cpu(cpu: &Cpu) {
         );
     }
 
+    // user data, stacks and heaps are mapped NO_EXECUTE, the bit is reserved until this is set
+    unsafe { Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    // so the kernel writing to a copy-on-write page faults too instead of writing through
+    unsafe { Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT)) };
+    // every CPU has to agree on it, or the same page gets cached differently on each
+    unsafe { Msr::new(IA32_PAT).write(PAT) };
+
     gdt::init(cpu.id);
     idt::init(cpu.id);
     interrupts::init();
     syscall::init(cpu.id);
+    pcr.online = true;
 
     info!("Initialized cpu {}", cpu.id);
     enable();
@@ -165,6 +188,11 @@ pub fn get_pcr_mut(cpu_id: u64) -> &'static mut ProcessorControlRegion {
     }
 }
 
+/// Returns the PCRs of the CPUs that finished initialization
+pub fn online_cpus() -> impl Iterator<Item = &'static ProcessorControlRegion> {
+    unsafe { PCRS.iter().filter(|pcr| pcr.online) }
+}
+
 pub fn current_pcr() -> &'static ProcessorControlRegion {
     let low: u32;
     let high: u32;
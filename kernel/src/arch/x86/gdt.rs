@@ -12,6 +12,8 @@ use crate::{arch::x86::cpu::PCRS, memory::address::VirtualAddress};
 
 use super::cpu::{current_pcr_mut, MAX_CPUS};
 
+/// Double faults get a stack of their own, a kernel stack overflow turns into one since the
+/// page fault can't be pushed on the overflowed stack
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const IA32_GS_BASE: u32 = 0xC0000101;
 pub const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
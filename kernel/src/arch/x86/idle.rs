@@ -0,0 +1,33 @@
+use core::arch::{asm, x86_64::__cpuid};
+
+use super::cpu::current_pcr;
+
+/// Body of every CPU's idle task, runs in ring 0 with interrupts enabled
+///
+/// The CPU sleeps until the next interrupt, either the timer or a reschedule IPI.
+pub extern "C" fn idle_loop() -> ! {
+    let mwait = has_mwait();
+
+    loop {
+        unsafe {
+            if mwait {
+                // arm the monitor on our current pid so another CPU clearing it also wakes us
+                let pcr = current_pcr();
+                asm!(
+                    "monitor",
+                    in("rax") &pcr.sched.current_pid,
+                    in("ecx") 0,
+                    in("edx") 0,
+                    options(nostack)
+                );
+                asm!("mwait", in("eax") 0, in("ecx") 0, options(nostack));
+            } else {
+                asm!("hlt", options(nomem, nostack));
+            }
+        }
+    }
+}
+
+fn has_mwait() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 3) != 0
+}
@@ -1,8 +1,16 @@
 use crate::arch::end_of_interrupt;
 use crate::arch::x86::cpu::{current_pcr, PCRS};
-use crate::arch::x86::interrupts::{ERROR_VECTOR, SPURIOUS_VECTOR, TIMER_VECTOR};
-use crate::interrupt;
-use crate::sched::scheduler::schedule;
+use crate::arch::x86::interrupts::{
+    ERROR_VECTOR, RESCHEDULE_VECTOR, SPURIOUS_VECTOR, TIMER_VECTOR,
+};
+use crate::arch::x86::structures::Registers;
+use crate::memory::address::VirtualAddress;
+use crate::random::add_interrupt_entropy;
+use crate::sched::scheduler::{current_task_mut, exit_current, reschedule, schedule};
+use crate::sched::slot::is_kernel_stack_guard;
+use crate::sched::task::ExitReason;
+use crate::sched::watchdog::report_stalled_cpu;
+use crate::{interrupt, interrupt_error, paranoid_interrupt};
 use log::{debug, info, warn};
 use spinning_top::Spinlock;
 use x86_64::registers::control::Cr2;
@@ -73,60 +81,88 @@ pub fn init(cpu_id: u32) {
     pcr.idt[TIMER_VECTOR as u8].set_handler_fn(timer_interrupt_handler);
     pcr.idt[ERROR_VECTOR as u8].set_handler_fn(error_interrupt_handler);
     pcr.idt[SPURIOUS_VECTOR as u8].set_handler_fn(spurious_interrupt_handler);
+    pcr.idt[RESCHEDULE_VECTOR as u8].set_handler_fn(reschedule_interrupt_handler);
     pcr.idt.load();
     debug!("IDT loaded")
 }
 
 interrupt!(timer_interrupt_handler, |interrupt_stack| {
     end_of_interrupt();
+    add_interrupt_entropy();
     schedule(interrupt_stack);
 });
 
+interrupt!(reschedule_interrupt_handler, |_interrupt_stack| {
+    end_of_interrupt();
+    reschedule();
+});
+
 // Exception Handlers
-extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+interrupt!(divide_error_handler, |registers| {
     set_last_exception(0, 0, 0);
-    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
-}
+    kill_user_task(0, registers, 0, ExitReason::ArithmeticError);
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", registers);
+});
 
 extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
     set_last_exception(1, 0, 0);
     panic!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
-    set_last_exception(2, 0, 0);
-    panic!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
-}
+// the watchdog sends one to a stuck CPU to see where it is
+paranoid_interrupt!(nmi_handler, |registers| {
+    if !report_stalled_cpu(registers) {
+        set_last_exception(2, 0, 0);
+        panic!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", registers);
+    }
+});
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     set_last_exception(3, 0, 0);
     info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+interrupt!(overflow_handler, |registers| {
     set_last_exception(4, 0, 0);
-    panic!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
-}
+    kill_user_task(4, registers, 0, ExitReason::ArithmeticError);
+    panic!("EXCEPTION: OVERFLOW\n{:#?}", registers);
+});
 
-extern "x86-interrupt" fn bound_range_handler(stack_frame: InterruptStackFrame) {
+interrupt!(bound_range_handler, |registers| {
     set_last_exception(5, 0, 0);
-    panic!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
-}
+    kill_user_task(5, registers, 0, ExitReason::Segfault);
+    panic!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", registers);
+});
 
-extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+interrupt!(invalid_opcode_handler, |registers| {
     set_last_exception(6, 0, 0);
-    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
-}
+    kill_user_task(6, registers, 0, ExitReason::IllegalInstruction);
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", registers);
+});
 
-extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+interrupt!(device_not_available_handler, |registers| {
     set_last_exception(7, 0, 0);
-    panic!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
-}
+    kill_user_task(7, registers, 0, ExitReason::IllegalInstruction);
+    panic!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", registers);
+});
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    // the page fault for a kernel stack overflow can't be pushed on the overflowed stack,
+    // so it ends up here on the double fault's own stack
+    let address = VirtualAddress::new(Cr2::read_raw() as usize);
+
+    if is_kernel_stack_guard(address) {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT\nkernel stack overflow in task {}\nAccessed Address: {:#x}\n{:#?}",
+            current_pcr().sched.current_pid.map_or(0, |pid| pid.as_usize()),
+            address.as_u64(),
+            stack_frame,
+        );
+    }
+
     panic!(
         "EXCEPTION: DOUBLE FAULT\n\
         Stack Frame: {:#?}\n\
@@ -145,83 +181,103 @@ extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame,
     );
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+interrupt_error!(segment_not_present_handler, |registers, error_code| {
     set_last_exception(11, error_code as u32, 0);
+    kill_user_task(11, registers, error_code, ExitReason::Segfault);
     panic!(
         "EXCEPTION: SEGMENT NOT PRESENT\nError Code: {}\n{:#?}",
-        error_code, stack_frame
+        error_code, registers
     );
-}
+});
 
-extern "x86-interrupt" fn stack_segment_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+interrupt_error!(stack_segment_fault_handler, |registers, error_code| {
     set_last_exception(12, error_code as u32, 0);
+    kill_user_task(12, registers, error_code, ExitReason::Segfault);
     panic!(
         "EXCEPTION: STACK SEGMENT FAULT\nError Code: {}\n{:#?}",
-        error_code, stack_frame
+        error_code, registers
     );
-}
+});
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+interrupt_error!(general_protection_fault_handler, |registers, error_code| {
     set_last_exception(13, error_code as u32, 0);
+    kill_user_task(13, registers, error_code, ExitReason::Segfault);
     panic!(
         "EXCEPTION: GENERAL PROTECTION FAULT\nError Code: {}\n{:#?}",
-        error_code, stack_frame
+        error_code, registers
     );
-}
+});
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    // if stack_frame.code_segment == GDT.1.user_code_selector {
-    //     remove_current_task();
-    //     error!("Page fault in user mode, removed running task");
-    //     debug!("{:#?}", stack_frame);
-    //     return;
-    // }
+interrupt_error!(page_fault_handler: PageFaultErrorCode, |registers, error_code| {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    let address = VirtualAddress::new(Cr2::read_raw() as usize);
+
+    if let Some(task) = current_task_mut() {
+        let memory = &mut task.memory_descriptor;
+        let handled = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            // a page shared with a forked task
+            error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && memory.copy_on_write(address).is_ok()
+        } else {
+            // a page of the task's heap, stack or BSS that nothing backs yet
+            memory.handle_fault(address).is_ok()
+        };
+
+        if handled {
+            return;
+        }
+
+        let reason = if task.user_stack.is_guard(address) {
+            ExitReason::StackOverflow
+        } else {
+            ExitReason::Segfault
+        };
+        set_last_exception(14, error_code.bits() as u32, address.as_u64());
+        kill_user_task(14, registers, error_code.bits(), reason);
+
+        if reason == ExitReason::StackOverflow {
+            panic!(
+                "EXCEPTION: PAGE FAULT\nstack overflow in task {}\nAccessed Address: {:#x}\n{:#?}",
+                task.pid,
+                address.as_u64(),
+                registers,
+            );
+        }
+    }
 
     panic!(
         "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
         Cr2::read(),
         error_code,
-        stack_frame,
+        registers,
     );
-}
+});
 
-extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+interrupt!(x87_floating_point_handler, |registers| {
     set_last_exception(16, 0, 0);
-    panic!("EXCEPTION: x87 FLOATING POINT\n{:#?}", stack_frame);
-}
+    kill_user_task(16, registers, 0, ExitReason::ArithmeticError);
+    panic!("EXCEPTION: x87 FLOATING POINT\n{:#?}", registers);
+});
 
-extern "x86-interrupt" fn alignment_check_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+interrupt_error!(alignment_check_handler, |registers, error_code| {
     set_last_exception(17, error_code as u32, 0);
+    kill_user_task(17, registers, error_code, ExitReason::BusError);
     panic!(
         "EXCEPTION: ALIGNMENT CHECK\nError Code: {}\n{:#?}",
-        error_code, stack_frame
+        error_code, registers
     );
-}
+});
 
 extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
     set_last_exception(18, 0, 0);
     panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+interrupt!(simd_floating_point_handler, |registers| {
     set_last_exception(19, 0, 0);
-    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
-}
+    kill_user_task(19, registers, 0, ExitReason::ArithmeticError);
+    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", registers);
+});
 
 extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
     set_last_exception(20, 0, 0);
@@ -251,6 +307,28 @@ extern "x86-interrupt" fn spurious_interrupt_handler(_frame: InterruptStackFrame
     warn!("Handling spurious");
 }
 
+/// Ends the current task if the exception came from user mode, otherwise returns so the
+/// handler can panic
+fn kill_user_task(vector: u32, registers: &Registers, error_code: u64, reason: ExitReason) {
+    if registers.iret.cs & 0x3 != 0x3 {
+        return;
+    }
+
+    if let Some(task) = current_task_mut() {
+        warn!(
+            "{} in task {} ({}), killing it: {}\nError Code: {:#x}\nCR2: {:#x}\n{:#?}",
+            exception_name(vector),
+            task.pid,
+            task.name,
+            reason,
+            error_code,
+            Cr2::read_raw(),
+            registers,
+        );
+        exit_current(reason);
+    }
+}
+
 fn exception_name(vector: u32) -> &'static str {
     match vector {
         0 => "Divide Error",
@@ -1,19 +1,30 @@
 use log::debug;
 use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder};
 
-use crate::{arch::x86::cpu::current_pcr, memory::paging::phys_to_virt};
+use crate::{
+    arch::x86::cpu::current_pcr,
+    memory::{
+        address::PhysicalAddress, paging::phys_to_virt, physical::PhysicalMemoryManager, PAGE_SIZE,
+    },
+};
 
-use super::cpu::MAX_CPUS;
+use super::{cpu::MAX_CPUS, memory::PMM, timer};
 
 static mut LAPICS: [Option<LocalApic>; MAX_CPUS] = [const { None }; MAX_CPUS];
 pub const TIMER_VECTOR: usize = 32;
 pub const ERROR_VECTOR: usize = TIMER_VECTOR + 1;
 pub const SPURIOUS_VECTOR: usize = ERROR_VECTOR + 1;
+pub const RESCHEDULE_VECTOR: usize = SPURIOUS_VECTOR + 1;
 
 pub(super) fn init() {
     let pcr = current_pcr();
     let phys_lapic = unsafe { xapic_base() };
     let virt_lapic = phys_to_virt(phys_lapic as usize);
+    // the registers usually sit past the end of RAM, but nothing may hand the frame out
+    PMM.lock().reserve_frame_range(
+        PhysicalAddress::new(phys_lapic as usize),
+        PhysicalAddress::new(phys_lapic as usize + PAGE_SIZE),
+    );
 
     debug!(
         "Initializing LAPIC - Physical: {:#x}, Virtual: {:#x}",
@@ -34,6 +45,8 @@ pub(super) fn init() {
         debug!("LAPIC enabled");
     }
 
+    timer::init(virt_lapic);
+
     debug!("Storing LAPIC instance");
 
     unsafe {
@@ -48,6 +61,22 @@ pub fn current_lapic_mut() -> &'static mut LocalApic {
     unsafe { LAPICS[pcr.lapic_id as usize].as_mut().unwrap() }
 }
 
+/// Interrupts another CPU so it picks up work queued for it, idle CPUs have no tick to
+/// notice it on
+pub fn send_reschedule(lapic_id: u64) {
+    unsafe {
+        current_lapic_mut().send_ipi(RESCHEDULE_VECTOR as u8, lapic_id as u32);
+    }
+}
+
+/// Interrupts another CPU even with its interrupts disabled, the watchdog uses it to find
+/// out where a stuck CPU is spinning
+pub fn send_nmi(lapic_id: u64) {
+    unsafe {
+        current_lapic_mut().send_nmi(lapic_id as u32);
+    }
+}
+
 #[macro_export]
 macro_rules! push_scratch {
     () => {
@@ -140,6 +169,97 @@ macro_rules! interrupt {
     };
 }
 
+/// Like `interrupt!` for the NMI, which can land anywhere, even between another entry and
+/// its swapgs, where the saved CS says kernel but GS is still the user's
+///
+/// The GS base is read instead: the kernel's is in the higher half. rbx remembers whether
+/// it was swapped, so it is swapped back before returning.
+#[macro_export]
+macro_rules! paranoid_interrupt {
+    ($name:ident, |$arg:ident| $code:block) => {
+
+        #[naked]
+        pub extern "x86-interrupt" fn $name(_frame: InterruptStackFrame) {
+            use crate::{pop_scratch, push_scratch, push_preserved, pop_preserved, arch::x86::{gdt::IA32_GS_BASE, structures::Registers}};
+
+            unsafe extern "C" fn inner($arg: &Registers) {
+                $code
+            }
+
+            unsafe {
+                core::arch::naked_asm!(
+                    "cld",
+                    push_preserved!(),
+                    push_scratch!(),
+                    "mov ecx, {gs_base}",
+                    "rdmsr",
+                    "xor ebx, ebx",
+                    "test edx, edx",
+                    "js 2f",
+                    "swapgs",
+                    "mov ebx, 1",
+                    "2:",
+                    "mov rdi, rsp",
+                    "call {inner}",
+                    "test ebx, ebx",
+                    "jz 3f",
+                    "swapgs",
+                    "3:",
+                    pop_scratch!(),
+                    pop_preserved!(),
+                    "iretq",
+                    gs_base = const IA32_GS_BASE,
+                    inner = sym inner,
+                );
+            }
+        }
+    };
+}
+
+/// Like `interrupt!` for the exceptions that push an error code, which is handed to the
+/// handler after the registers
+///
+/// The error code sits between the saved registers and the iret frame, so it's swapped
+/// with rbx, leaving rbx where `push_preserved!` would have put it.
+#[macro_export]
+macro_rules! interrupt_error {
+    ($name:ident, |$arg:ident, $error:ident| $code:block) => {
+        $crate::interrupt_error!($name: u64, |$arg, $error| $code);
+    };
+    ($name:ident: $error_type:ty, |$arg:ident, $error:ident| $code:block) => {
+
+        #[naked]
+        pub extern "x86-interrupt" fn $name(_frame: InterruptStackFrame, _error_code: $error_type) {
+            use crate::{pop_scratch, push_scratch, pop_preserved, swapgs, arch::x86::structures::Registers};
+
+            unsafe extern "C" fn inner($arg: &Registers, $error: u64) {
+                $code
+            }
+
+            unsafe {
+                core::arch::naked_asm!(
+                    "cld",
+                    swapgs!(error_code),
+                    "xchg [rsp], rbx",
+                    "push rbp",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+                    push_scratch!(),
+                    "mov rdi, rsp",
+                    "mov rsi, rbx",
+                    "call {inner}",
+                    pop_scratch!(),
+                    pop_preserved!(),
+                    "iretq",
+                    inner = sym inner,
+                );
+            }
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! swapgs {
     () => {
@@ -150,4 +270,13 @@ macro_rules! swapgs {
         2:
     "
     };
+    // the error code comes before the iret frame
+    (error_code) => {
+        "
+        test QWORD PTR [rsp + 16], 0x3
+        jz 2f
+        swapgs
+        2:
+    "
+    };
 }
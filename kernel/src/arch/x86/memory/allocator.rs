@@ -7,12 +7,13 @@ use x86_64::{
 use crate::memory::{
     address::VirtualAddress,
     paging::{PageFlags, VirtualMemoryManager},
-    physical::PhysicalMemoryManager,
+    physical::{FrameOwner, PhysicalMemoryManager},
 };
 
 use super::{PMM, VMM};
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
+// in the kernel half, so every address space shares it
+pub const HEAP_START: usize = 0xffff_9000_0000_0000;
 pub const HEAP_SIZE: usize = 10 * 1024 * 1024; // 100 KiB
 
 #[global_allocator]
@@ -28,7 +29,7 @@ pub fn init() {
     };
 
     for page in page_range {
-        let frame = PMM.lock().allocate().unwrap();
+        let frame = PMM.lock().allocate(FrameOwner::KernelHeap).unwrap();
         VMM.lock()
             .map(
                 VirtualAddress::new(page.start_address().as_u64() as usize),
@@ -4,6 +4,8 @@ use paging::X86VirtualMemoryManager;
 use physical::X86PhysicalMemoryManager;
 use spinning_top::Spinlock;
 
+use crate::memory::{address::VirtualAddress, physical::PhysicalMemoryManager};
+
 pub mod allocator;
 pub mod paging;
 pub mod physical;
@@ -24,3 +26,16 @@ lazy_static! {
         Spinlock::new(vmm)
     };
 }
+
+/// Unmaps `size` bytes starting at `start` and gives the frames behind them back to the
+/// PMM, returns how many frames were freed
+pub fn release_range(start: VirtualAddress, size: usize) -> usize {
+    let frames = VMM.lock().unmap_range(start, size);
+    let mut pmm = PMM.lock();
+
+    for frame in frames.iter() {
+        pmm.free(*frame);
+    }
+
+    frames.len()
+}
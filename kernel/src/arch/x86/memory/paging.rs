@@ -1,12 +1,15 @@
 use super::PMM;
 use crate::memory::{
     address::{PhysicalAddress, VirtualAddress},
-    paging::{MapError, PageFlags, UnmapError, VirtualMemoryManager},
+    paging::{phys_to_virt, MapError, PageFlags, UnmapError, VirtualMemoryManager},
+    physical::{FrameOwner, PhysicalMemoryManager},
     MEMORY_OFFSET, PAGE_SIZE,
 };
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use log::{debug, warn};
 use x86_64::{
-    registers::control::Cr3,
+    registers::control::{Cr3, Cr3Flags},
     structures::paging::{
         mapper::{MapToError, TranslateResult, UnmapError as X86UnmapError},
         Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
@@ -14,26 +17,128 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
+/// First PML4 entry of the kernel half, every address space shares the entries from it on
+const KERNEL_HALF: usize = 256;
+
+/// Physical address of the kernel's PML4, so switching to it needs no lock
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug)]
 pub struct X86VirtualMemoryManager {
     page_table: OffsetPageTable<'static>,
+    /// Holds the PML4, loaded into CR3 to switch to this address space
+    frame: PhysFrame,
 }
 
 impl X86VirtualMemoryManager {
+    /// The kernel's address space, the one the bootloader left in CR3
     pub fn new() -> Self {
         let (l4_table_frame, _) = Cr3::read();
         let phys = l4_table_frame.start_address();
-        let memory_offset = *MEMORY_OFFSET;
-        let virt = VirtAddr::new(memory_offset) + phys.as_u64();
-        let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-        let page_table =
-            unsafe { OffsetPageTable::new(&mut *page_table_ptr, VirtAddr::new(memory_offset)) };
+        let page_table = unsafe { Self::page_table_at(phys) };
 
         debug!(
             "Created VMM with page table at physical address {:#x}",
             phys.as_u64()
         );
-        Self { page_table }
+        let mut vmm = Self {
+            page_table,
+            frame: l4_table_frame,
+        };
+        vmm.fill_kernel_half();
+        KERNEL_PML4.store(phys.as_u64(), Ordering::Relaxed);
+
+        vmm
+    }
+
+    /// Creates an address space with nothing in the user half and the kernel half of `kernel`
+    pub fn new_user(kernel: &Self) -> Result<Self, MapError> {
+        let phys = PMM
+            .lock()
+            .allocate(FrameOwner::PageTable)
+            .map_err(|_| MapError::NoPhysicalMemory)?;
+        let phys = PhysAddr::new(phys.as_u64());
+        let mut page_table = unsafe {
+            (phys_to_virt(phys.as_u64() as usize) as *mut PageTable).write(PageTable::new());
+            Self::page_table_at(phys)
+        };
+        let kernel_table = kernel.page_table.level_4_table();
+        let table = page_table.level_4_table_mut();
+
+        for i in KERNEL_HALF..512 {
+            table[i] = kernel_table[i].clone();
+        }
+
+        Ok(Self {
+            page_table,
+            frame: PhysFrame::containing_address(phys),
+        })
+    }
+
+    unsafe fn page_table_at(phys: PhysAddr) -> OffsetPageTable<'static> {
+        let memory_offset = *MEMORY_OFFSET;
+        let virt = VirtAddr::new(memory_offset) + phys.as_u64();
+        let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+        OffsetPageTable::new(&mut *page_table_ptr, VirtAddr::new(memory_offset))
+    }
+
+    /// Gives every kernel half PML4 entry a table, so kernel mappings made later land in
+    /// tables every address space shares instead of in a PML4 entry only this one has
+    fn fill_kernel_half(&mut self) {
+        let mut pmm = PMM.lock();
+        let table = self.page_table.level_4_table_mut();
+
+        for entry in table.iter_mut().skip(KERNEL_HALF) {
+            if !entry.is_unused() {
+                continue;
+            }
+
+            let frame = pmm
+                .allocate(FrameOwner::PageTable)
+                .expect("no memory left for the kernel's page tables");
+            unsafe { (phys_to_virt(frame.as_usize()) as *mut PageTable).write(PageTable::new()) };
+            entry.set_addr(
+                PhysAddr::new(frame.as_u64()),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            );
+        }
+    }
+
+    /// Switches the current CPU to this address space
+    pub fn activate(&self) {
+        load_cr3(self.frame);
+    }
+
+    /// Switches the current CPU to the kernel's address space, for tasks that have none
+    pub fn activate_kernel() {
+        let phys = PhysAddr::new(KERNEL_PML4.load(Ordering::Relaxed));
+        load_cr3(PhysFrame::containing_address(phys));
+    }
+
+    /// Frees the page tables of the user half and the PML4, returns how many frames that was
+    ///
+    /// The pages themselves have to be unmapped and freed first, and no CPU may have the
+    /// address space loaded.
+    pub fn release(mut self) -> usize {
+        let mut pmm = PMM.lock();
+        let mut frames = 0;
+        let table = self.page_table.level_4_table_mut();
+
+        for entry in table.iter_mut().take(KERNEL_HALF) {
+            if entry.is_unused() {
+                continue;
+            }
+
+            frames += free_table(&mut *pmm, entry.addr(), 3);
+            entry.set_unused();
+        }
+
+        pmm.free(PhysicalAddress::new(
+            self.frame.start_address().as_u64() as usize
+        ));
+
+        frames + 1
     }
 
     pub fn page_flags(&self, virtual_addr: VirtualAddress) -> Option<PageTableFlags> {
@@ -65,6 +170,53 @@ impl X86VirtualMemoryManager {
         Ok(())
     }
 
+    /// Unmaps a range of pages, returning the frames that backed the mapped ones
+    pub fn unmap_range(&mut self, virt_start: VirtualAddress, size: usize) -> Vec<PhysicalAddress> {
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut frames = Vec::new();
+
+        for i in 0..pages {
+            let virt_addr = VirtualAddress::new(virt_start.as_usize() + i * PAGE_SIZE);
+
+            if let Some(phys_addr) = self.get_physical_address(virt_addr) {
+                if self.unmap(virt_addr).is_ok() {
+                    frames.push(phys_addr);
+                }
+            }
+        }
+
+        frames
+    }
+
+    /// Changes the flags of a mapped page, e.g. to share it copy-on-write
+    pub fn update_flags(
+        &mut self,
+        virtual_addr: VirtualAddress,
+        flags: PageFlags,
+    ) -> Result<(), MapError> {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virtual_addr.as_u64()));
+
+        match unsafe { self.page_table.update_flags(page, flags.into()) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(_) => {
+                warn!(
+                    "Cannot update flags: {:#x} is not mapped",
+                    virtual_addr.as_u64()
+                );
+                Err(MapError::InvalidAddress)
+            }
+        }
+    }
+
+    /// Whether the page is shared with a forked task and has to be copied before a write
+    pub fn is_copy_on_write(&self, virtual_addr: VirtualAddress) -> bool {
+        self.page_flags(virtual_addr)
+            .is_some_and(|flags| flags.contains(PageTableFlags::BIT_9))
+    }
+
     /// Checks if a virtual address is mapped
     pub fn is_mapped(&self, virtual_addr: VirtualAddress) -> bool {
         let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virtual_addr.as_u64()));
@@ -184,6 +336,36 @@ impl VirtualMemoryManager for X86VirtualMemoryManager {
     }
 }
 
+fn load_cr3(frame: PhysFrame) {
+    let (current, _) = Cr3::read();
+
+    // reloading it would flush the TLB for nothing
+    if current != frame {
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+}
+
+/// Frees a page table and the tables below it, `level` is 1 for a table of pages
+fn free_table(pmm: &mut impl PhysicalMemoryManager, table: PhysAddr, level: usize) -> usize {
+    let mut frames = 0;
+
+    if level > 1 {
+        let entries = unsafe { &*(phys_to_virt(table.as_u64() as usize) as *const PageTable) };
+
+        for entry in entries.iter() {
+            if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                continue;
+            }
+
+            frames += free_table(pmm, entry.addr(), level - 1);
+        }
+    }
+
+    pmm.free(PhysicalAddress::new(table.as_u64() as usize));
+
+    frames + 1
+}
+
 #[cfg(target_arch = "x86_64")]
 impl From<PageFlags> for PageTableFlags {
     fn from(flags: PageFlags) -> Self {
@@ -220,6 +402,9 @@ impl From<PageFlags> for PageTableFlags {
         if flags.contains(PageFlags::NO_EXECUTE) {
             x86_flags |= PageTableFlags::NO_EXECUTE;
         }
+        if flags.contains(PageFlags::COPY_ON_WRITE) {
+            x86_flags |= PageTableFlags::BIT_9;
+        }
 
         x86_flags
     }
@@ -1,21 +1,51 @@
+use core::mem::size_of;
+
+use alloc::collections::btree_map::BTreeMap;
 use bitmap_allocator::BitAlloc;
 use limine::memory_map::EntryType;
-use log::{debug, warn};
+use log::{debug, error, warn};
 use x86_64::{
     structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
-use crate::memory::{
-    address::PhysicalAddress,
-    physical::{FrameAllocationError, PhysicalMemoryManager, FRAME_ALLOCATOR},
-    MEMORY_MAP, PAGE_SIZE,
+use crate::{
+    memory::{
+        address::PhysicalAddress,
+        paging::phys_to_virt,
+        physical::{FrameAllocationError, FrameOwner, PhysicalMemoryManager, FRAME_ALLOCATOR},
+        MEMORY_MAP, PAGE_SIZE,
+    },
+    sched::pid::Pid,
 };
 
+/// Tag of a frame nobody owns, free or never usable
+const NO_OWNER: u32 = 0;
+/// Tags from here on are `FrameOwner::Task`, with the PID added
+const TASK_TAG: u32 = 16;
+
+/// Memory map entries that are reserved for good, see `reserve_frame_range`
+const RESERVED_TYPES: [EntryType; 4] = [
+    EntryType::ACPI_RECLAIMABLE,
+    EntryType::ACPI_NVS,
+    EntryType::KERNEL_AND_MODULES,
+    EntryType::FRAMEBUFFER,
+];
+
 #[derive(Debug)]
 pub struct X86PhysicalMemoryManager {
     total_frames: usize,
     usable_frames: usize,
+    /// References beyond the first to shared frames, by frame number, most frames have a
+    /// single owner and aren't in it
+    shared: BTreeMap<usize, usize>,
+    /// Tag of each frame's owner, by frame number, see `tag`
+    ///
+    /// It only goes up to the last usable or kernel frame, reserved and MMIO ranges can sit
+    /// far above RAM and frames past it are never allocated. It lives in frames taken
+    /// straight from the bitmap, the PMM exists before the heap.
+    owners: &'static mut [u32],
+    bad_frees: usize,
 }
 
 impl X86PhysicalMemoryManager {
@@ -51,20 +81,89 @@ impl X86PhysicalMemoryManager {
             total_frames, usable_frames
         );
 
-        Self {
+        let tracked_frames = entries
+            .iter()
+            .filter(|e| {
+                e.entry_type == EntryType::USABLE || e.entry_type == EntryType::KERNEL_AND_MODULES
+            })
+            .map(|e| (e.base + e.length) as usize)
+            .max()
+            .unwrap()
+            .div_ceil(PAGE_SIZE);
+        let table_frames = (tracked_frames * size_of::<u32>()).div_ceil(PAGE_SIZE);
+        let table_start = allocator
+            .alloc_contiguous(None, table_frames, 0)
+            .expect("no memory left for the frame owner table");
+        drop(allocator);
+
+        let owners = unsafe {
+            core::slice::from_raw_parts_mut(
+                phys_to_virt(table_start * PAGE_SIZE) as *mut u32,
+                tracked_frames,
+            )
+        };
+        owners.fill(NO_OWNER);
+        owners[table_start..table_start + table_frames].fill(tag(FrameOwner::Kernel));
+
+        let mut pmm = Self {
             total_frames,
             usable_frames,
+            shared: BTreeMap::new(),
+            owners,
+            bad_frees: 0,
+        };
+
+        // none of them are usable to begin with, reserving them catches frees of their
+        // frames
+        for entry in entries.iter() {
+            if RESERVED_TYPES.contains(&entry.entry_type) {
+                pmm.reserve_frame_range(
+                    PhysicalAddress::new(entry.base as usize),
+                    PhysicalAddress::new((entry.base + entry.length) as usize),
+                );
+            }
         }
+
+        pmm
+    }
+}
+
+fn tag(owner: FrameOwner) -> u32 {
+    match owner {
+        FrameOwner::Kernel => 1,
+        FrameOwner::KernelHeap => 2,
+        FrameOwner::PageTable => 3,
+        FrameOwner::Shared => 4,
+        FrameOwner::Dma => 5,
+        FrameOwner::Reserved => 6,
+        FrameOwner::Orphaned => 7,
+        FrameOwner::Task(pid) => TASK_TAG + pid.as_usize() as u32,
+    }
+}
+
+fn owner_of(tag: u32) -> Option<FrameOwner> {
+    match tag {
+        NO_OWNER => None,
+        1 => Some(FrameOwner::Kernel),
+        2 => Some(FrameOwner::KernelHeap),
+        3 => Some(FrameOwner::PageTable),
+        4 => Some(FrameOwner::Shared),
+        5 => Some(FrameOwner::Dma),
+        6 => Some(FrameOwner::Reserved),
+        7 => Some(FrameOwner::Orphaned),
+        tag if tag >= TASK_TAG => Some(FrameOwner::Task(Pid::new((tag - TASK_TAG) as usize))),
+        _ => None,
     }
 }
 
 impl PhysicalMemoryManager for X86PhysicalMemoryManager {
-    fn allocate(&mut self) -> Result<PhysicalAddress, FrameAllocationError> {
+    fn allocate(&mut self, owner: FrameOwner) -> Result<PhysicalAddress, FrameAllocationError> {
         debug!("Allocating new frame");
         let mut allocator = FRAME_ALLOCATOR.lock();
 
         match allocator.alloc() {
             Some(frame) => {
+                self.owners[frame] = tag(owner);
                 let addr = PhysicalAddress::new(frame * PAGE_SIZE);
                 debug!("Allocated frame at physical address {:#x}", addr.as_u64());
                 Ok(addr)
@@ -79,12 +178,15 @@ impl PhysicalMemoryManager for X86PhysicalMemoryManager {
     fn allocate_contiguous(
         &mut self,
         size: usize,
+        owner: FrameOwner,
     ) -> Result<PhysicalAddress, FrameAllocationError> {
         let frames_needed = (size + PAGE_SIZE - 1) / PAGE_SIZE;
         let mut allocator = FRAME_ALLOCATOR.lock();
 
         match allocator.alloc_contiguous(None, frames_needed, 0) {
             Some(start_frame) => {
+                self.owners[start_frame..start_frame + frames_needed].fill(tag(owner));
+
                 let addr = PhysicalAddress::new(start_frame * PAGE_SIZE);
                 debug!(
                     "Allocated {} contiguous frames starting at {:#x}",
@@ -102,9 +204,51 @@ impl PhysicalMemoryManager for X86PhysicalMemoryManager {
 
     fn free(&mut self, frame: PhysicalAddress) {
         let frame_number = frame.as_usize() / PAGE_SIZE;
+
+        if let Some(extra) = self.shared.get_mut(&frame_number) {
+            *extra -= 1;
+
+            if *extra == 0 {
+                self.shared.remove(&frame_number);
+            }
+
+            debug!("Dropped a reference to frame at {:#x}", frame.as_u64());
+            return;
+        }
+
+        let owner = match self.owner(frame) {
+            Some(FrameOwner::Reserved) => {
+                error!("Tried to free reserved frame at {:#x}", frame.as_u64());
+                self.bad_frees += 1;
+                return;
+            }
+            Some(owner) => owner,
+            None => {
+                error!(
+                    "Tried to free frame at {:#x}, which isn't allocated",
+                    frame.as_u64()
+                );
+                self.bad_frees += 1;
+                return;
+            }
+        };
+
+        self.owners[frame_number] = NO_OWNER;
         let mut allocator = FRAME_ALLOCATOR.lock();
         allocator.dealloc(frame_number);
-        debug!("Freed frame at {:#x}", frame.as_u64());
+        debug!("Freed frame at {:#x} of {}", frame.as_u64(), owner);
+    }
+
+    fn share(&mut self, frame: PhysicalAddress) {
+        *self.shared.entry(frame.as_usize() / PAGE_SIZE).or_insert(0) += 1;
+    }
+
+    fn ref_count(&self, frame: PhysicalAddress) -> usize {
+        1 + self
+            .shared
+            .get(&(frame.as_usize() / PAGE_SIZE))
+            .copied()
+            .unwrap_or(0)
     }
 
     fn available_memory(&self) -> usize {
@@ -129,13 +273,67 @@ impl PhysicalMemoryManager for X86PhysicalMemoryManager {
     }
 
     fn reserve_frame_range(&mut self, start: PhysicalAddress, end: PhysicalAddress) {
-        todo!()
+        let first = start.as_usize() / PAGE_SIZE;
+        let last = end.as_usize().div_ceil(PAGE_SIZE);
+        // MMIO can sit past the last frame of RAM, the allocator never hands those out
+        let frames = first.min(self.owners.len())..last.min(self.owners.len());
+
+        if let Some(frame) = frames.clone().find(|frame| {
+            !matches!(
+                owner_of(self.owners[*frame]),
+                None | Some(FrameOwner::Reserved)
+            )
+        }) {
+            warn!(
+                "Reserving {:#x} - {:#x}, frame {:#x} in it is allocated to {}",
+                start.as_u64(),
+                end.as_u64(),
+                frame * PAGE_SIZE,
+                owner_of(self.owners[frame]).unwrap()
+            );
+        }
+
+        if !frames.is_empty() {
+            FRAME_ALLOCATOR.lock().remove(frames.clone());
+            self.owners[frames].fill(tag(FrameOwner::Reserved));
+        }
+
+        debug!("Reserved {:#x} - {:#x}", start.as_u64(), end.as_u64());
+    }
+
+    fn owner(&self, frame: PhysicalAddress) -> Option<FrameOwner> {
+        self.owners
+            .get(frame.as_usize() / PAGE_SIZE)
+            .copied()
+            .and_then(owner_of)
+    }
+
+    fn set_owner(&mut self, frame: PhysicalAddress, owner: FrameOwner) {
+        if let Some(tag_slot) = self.owners.get_mut(frame.as_usize() / PAGE_SIZE) {
+            if *tag_slot != NO_OWNER {
+                *tag_slot = tag(owner);
+            }
+        }
+    }
+
+    fn usage(&self) -> BTreeMap<FrameOwner, usize> {
+        let mut usage = BTreeMap::new();
+
+        for owner in self.owners.iter().filter_map(|tag| owner_of(*tag)) {
+            *usage.entry(owner).or_insert(0) += 1;
+        }
+
+        usage
+    }
+
+    fn bad_frees(&self) -> usize {
+        self.bad_frees
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for X86PhysicalMemoryManager {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        match self.allocate() {
+        match self.allocate(FrameOwner::PageTable) {
             Ok(f) => Some(PhysFrame::from_start_address(PhysAddr::new(f.as_u64())).unwrap()),
             Err(_) => None,
         }
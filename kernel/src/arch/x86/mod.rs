@@ -1,10 +1,13 @@
 pub mod cpu;
 pub mod gdt;
+pub mod idle;
 pub mod idt;
 pub mod interrupts;
 pub mod memory;
+pub mod random;
 pub mod sched;
 pub mod structures;
+pub mod timer;
 
 pub fn init() {
     memory::allocator::init();
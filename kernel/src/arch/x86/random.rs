@@ -0,0 +1,83 @@
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count, _rdtsc},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::instructions::random::RdRand;
+
+const RDSEED_RETRIES: usize = 10;
+
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+static HAS_RDSEED: AtomicBool = AtomicBool::new(false);
+
+/// Checks which of RDRAND and RDSEED the CPU has, once before either is read
+pub fn probe() {
+    HAS_RDRAND.store(RdRand::new().is_some(), Ordering::Relaxed);
+    HAS_RDSEED.store(has_rdseed(), Ordering::Relaxed);
+}
+
+/// Reads a value from RDRAND, if the CPU supports it
+pub fn rdrand() -> Option<u64> {
+    if !HAS_RDRAND.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    // only fails when the DRNG is momentarily drained, the caller has other inputs
+    let value: u64;
+    let ok: u8;
+
+    unsafe {
+        asm!(
+            "rdrand {value}",
+            "setc {ok}",
+            value = out(reg) value,
+            ok = out(reg_byte) ok,
+            options(nomem, nostack)
+        );
+    }
+
+    (ok == 1).then_some(value)
+}
+
+/// Reads a value from RDSEED, if the CPU supports it
+pub fn rdseed() -> Option<u64> {
+    if !HAS_RDSEED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    // RDSEED can transiently fail when the entropy source is drained, so retry a few times
+    for _ in 0..RDSEED_RETRIES {
+        let value: u64;
+        let ok: u8;
+
+        unsafe {
+            asm!(
+                "rdseed {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack)
+            );
+        }
+
+        if ok == 1 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Reads the time stamp counter
+pub fn timestamp() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn has_rdseed() -> bool {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+
+    max_leaf >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0
+}
@@ -1,17 +1,60 @@
-use core::arch::asm;
+use core::arch::naked_asm;
 
-use super::{gdt::set_tss_kernel_stack, structures};
-use alloc::collections::vec_deque::VecDeque;
+use super::{cpu::current_pcr_mut, gdt::set_tss_kernel_stack, structures};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64};
+
+use spinning_top::Spinlock;
 use structures::Registers;
 
-use crate::sched::{pid::Pid, task::Task};
+use crate::{
+    memory::address::VirtualAddress,
+    pop_preserved, pop_scratch, push_preserved,
+    sched::{pid::Pid, queue::RunQueue, task::Task},
+    timer::{TimerId, TimerQueue},
+};
 
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct SchedulerInfo {
     pub current_pid: Option<Pid>,
+    /// Timer interrupts taken, the timer only fires when something is due
     pub pit_ticks: u64,
-    pub run_queue: VecDeque<Pid>,
+    /// Locked because other CPUs pull tasks from it when balancing
+    pub run_queue: Spinlock<RunQueue>,
+    /// Deadline tasks pinned to this CPU, they never go through the run queue
+    pub realtime: Spinlock<Vec<Pid>>,
+    /// Tasks pulled from other CPUs by periodic balancing
+    pub migrations: u64,
+    /// Tasks stolen from other CPUs while this one was idle
+    pub steals: u64,
+    /// Where `switch_to` saves a context that is never resumed
+    pub abandoned_rsp: u64,
+    /// Pid whose kernel stack this CPU is on, 0 before the first switch
+    ///
+    /// Unlike `current_pid` it survives the task being killed, and `context_switch` only
+    /// updates it once the old stack has been left, so the reaper can tell when a dead
+    /// task's stack is free.
+    pub on_stack: usize,
+    /// Sleeps, timeouts and the running task's time slice
+    pub timers: Spinlock<TimerQueue>,
+    /// Ends the running task's time slice, none while idle
+    pub slice_timer: Option<TimerId>,
+    /// Time up to which the running task has been charged, in ticks
+    pub accounted_at: u64,
+    pub next_balance: u64,
+    pub next_aging: u64,
+    pub next_watchdog: u64,
+    /// Time of the last pass through the scheduler, the watchdog's heartbeat
+    pub last_scheduled: u64,
+    /// Set by the watchdog when it reported this CPU as stuck, until it schedules again
+    pub stalled: AtomicBool,
+    /// Entries into the scheduler from user mode or the idle loop, where the CPU holds no
+    /// reference to a task, the reaper waits for one on every CPU before freeing a task
+    pub quiescent: AtomicU64,
+    /// Registers this CPU was stuck with, left by its NMI for another CPU to print since
+    /// the NMI may have interrupted the logger
+    pub stall_registers: Spinlock<Option<Registers>>,
 }
 
 impl SchedulerInfo {
@@ -19,84 +62,101 @@ impl SchedulerInfo {
         Self {
             current_pid: None,
             pit_ticks: 0,
-            run_queue: VecDeque::new(),
+            run_queue: Spinlock::new(RunQueue::new()),
+            realtime: Spinlock::new(Vec::new()),
+            migrations: 0,
+            steals: 0,
+            abandoned_rsp: 0,
+            on_stack: 0,
+            timers: Spinlock::new(TimerQueue::new()),
+            slice_timer: None,
+            accounted_at: 0,
+            next_balance: 0,
+            next_aging: 0,
+            next_watchdog: 0,
+            last_scheduled: 0,
+            stalled: AtomicBool::new(false),
+            quiescent: AtomicU64::new(0),
+            stall_registers: Spinlock::new(None),
         }
     }
 }
 
-pub unsafe fn switch_to(prev: Option<&mut Task>, next: &Task, current_stack_frame: &Registers) {
-    if let Some(t) = prev {
-        save(&mut t.context, current_stack_frame);
-    }
-
-    set_tss_kernel_stack(next.kernel_stack.top());
-    restore(&next.context);
+/// Kernel-mode context of a task that isn't running
+///
+/// Everything else, including the user registers, is saved on the task's kernel stack.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Context {
+    pub rsp: u64,
 }
 
-pub unsafe fn save(context: &mut Registers, stack_frame: &Registers) {
-    context.iret.rip = stack_frame.iret.rip;
-    context.iret.rsp = stack_frame.iret.rsp;
-    context.iret.rflags = stack_frame.iret.rflags;
-    context.iret.cs = stack_frame.iret.cs;
-    context.iret.ss = stack_frame.iret.ss;
-    context.preserved.r15 = stack_frame.preserved.r15;
-    context.preserved.r14 = stack_frame.preserved.r14;
-    context.preserved.r13 = stack_frame.preserved.r13;
-    context.preserved.r12 = stack_frame.preserved.r12;
-    context.preserved.rbp = stack_frame.preserved.rbp;
-    context.preserved.rbx = stack_frame.preserved.rbx;
-    context.scratch.rax = stack_frame.scratch.rax;
-    context.scratch.rcx = stack_frame.scratch.rcx;
-    context.scratch.rdx = stack_frame.scratch.rdx;
-    context.scratch.rdi = stack_frame.scratch.rdi;
-    context.scratch.rsi = stack_frame.scratch.rsi;
-    context.scratch.r8 = stack_frame.scratch.r8;
-    context.scratch.r9 = stack_frame.scratch.r9;
-    context.scratch.r10 = stack_frame.scratch.r10;
-    context.scratch.r11 = stack_frame.scratch.r11;
+impl Context {
+    /// Builds the initial kernel stack of a task, the first switch to it goes through
+    /// `task_entry`, which drops into `registers` with an iretq
+    pub fn new(stack_top: VirtualAddress, registers: Registers) -> Self {
+        unsafe {
+            let frame = (stack_top.as_u64() as *mut Registers).sub(1);
+            frame.write(registers);
+
+            let mut rsp = frame as *mut u64;
+            rsp = rsp.sub(1);
+            rsp.write(task_entry as usize as u64);
+
+            // callee-saved registers popped by `context_switch`
+            for _ in 0..6 {
+                rsp = rsp.sub(1);
+                rsp.write(0);
+            }
+
+            Self { rsp: rsp as u64 }
+        }
+    }
 }
 
-// IMPORTANT: any acquired locks, or anything you must do before switching tasks,
-// must be released or done here, after this the kernel will jump to the task's instruction pointer
-pub unsafe fn restore(context: &Registers) -> ! {
-    const PRESERVED_OFFSET: u8 = 0x48;
-    const SCRATCH_OFFSET: u8 = 0x00;
-    const IRET_OFFSET: u8 = 0x78;
-    const SS_OFFSET: u8 = IRET_OFFSET + 0x20;
-    asm!(
-        // Setup iret stack frame first before we touch any registers
-        "push [r12 + {ss_offset}]",          // SS
-        "push [r12 + {iret_offset} + 0x18]", // RSP
-        "push [r12 + {iret_offset} + 0x10]", // RFLAGS
-        "push [r12 + {iret_offset} + 0x8]",  // CS
-        "push [r12 + {iret_offset}]",        // RIP
+/// Switches to `next`'s kernel stack, returns once `prev` is scheduled again
+///
+/// With no `prev` the current context is abandoned, like the boot stack or an exited task.
+pub unsafe fn switch_to(prev: Option<&mut Task>, next: &Task) {
+    let pcr = current_pcr_mut();
+    set_tss_kernel_stack(next.kernel_stack.top());
+    next.memory_descriptor.activate();
 
-        // Restore scratch registers
-        "mov r11, [r12 + {scratch_offset}]",
-        "mov r10, [r12 + {scratch_offset} + 0x8]",
-        "mov r9, [r12 + {scratch_offset} + 0x10]",
-        "mov r8, [r12 + {scratch_offset} + 0x18]",
-        "mov rsi, [r12 + {scratch_offset} + 0x20]",
-        "mov rdi,  [r12 + {scratch_offset} + 0x28]",
-        "mov rdx,  [r12 + {scratch_offset} + 0x30]",
-        "mov rcx, [r12 + {scratch_offset} + 0x38]",
-        "mov rax, [r12 + {scratch_offset} + 0x40]",
+    let prev_rsp = match prev {
+        Some(task) => &mut task.context.rsp as *mut u64,
+        None => &mut pcr.sched.abandoned_rsp as *mut u64,
+    };
 
-        // Restore preserved registers LAST
-        // We're using r12 as a temporary, so restore it last
-        "mov r15, [r12 + {preserved_offset}]",
-        "mov r14, [r12 + {preserved_offset} + 0x8]",
-        "mov r13, [r12 + {preserved_offset} + 0x10]",
-        "mov rbp, [r12 + {preserved_offset} + 0x20]",
-        "mov rbx, [r12 + {preserved_offset} + 0x28]",
-        "mov r12, [r12 + {preserved_offset} + 0x18]", // Restore r12 last
+    context_switch(
+        prev_rsp,
+        next.context.rsp,
+        &mut pcr.sched.on_stack,
+        next.pid.as_usize(),
+    );
+}
 
-        "iretq",
-        in("r12") context,
-        ss_offset = const SS_OFFSET,
-        preserved_offset = const PRESERVED_OFFSET,
-        scratch_offset = const SCRATCH_OFFSET,
-        iret_offset = const IRET_OFFSET,
-        options(noreturn)
+/// Saves the callee-saved registers and stack pointer into `prev_rsp` and resumes the
+/// context saved at `next_rsp`, storing `next_pid` in `on_stack` once off the old stack
+#[naked]
+unsafe extern "C" fn context_switch(
+    prev_rsp: *mut u64,
+    next_rsp: u64,
+    on_stack: *mut usize,
+    next_pid: usize,
+) {
+    naked_asm!(
+        push_preserved!(),
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "mov [rdx], rcx",
+        pop_preserved!(),
+        "ret",
     );
 }
+
+/// First code a new task runs in the kernel, `Context::new` left its registers right above
+/// the stack pointer
+#[naked]
+unsafe extern "C" fn task_entry() {
+    naked_asm!(pop_scratch!(), pop_preserved!(), "iretq");
+}
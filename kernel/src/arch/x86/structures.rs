@@ -2,7 +2,7 @@ use log::info;
 
 use super::cpu::current_pcr;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[repr(C)]
 pub struct Registers {
     pub scratch: Scratch,
@@ -10,7 +10,7 @@ pub struct Registers {
     pub iret: Iret,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[repr(C)]
 pub struct Scratch {
     pub r11: u64,
@@ -24,7 +24,7 @@ pub struct Scratch {
     pub rax: u64,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[repr(C)]
 pub struct Preserved {
     pub r15: u64,
@@ -35,7 +35,7 @@ pub struct Preserved {
     pub rbx: u64,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[repr(C)]
 pub struct Iret {
     pub rip: u64,
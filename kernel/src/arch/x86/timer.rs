@@ -0,0 +1,212 @@
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use log::info;
+use x86_64::{instructions::port::Port, registers::model_specific::Msr};
+
+use super::interrupts::TIMER_VECTOR;
+
+/// Resolution of kernel time, a tick is 1/HZ of a second
+pub const HZ: u64 = 1000;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// Length of the PIT one-shot the LAPIC timer and TSC are measured against
+const CALIBRATION_MS: u64 = 10;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const X2APIC_ENABLE: u64 = 1 << 10;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const LVT_TIMER: u32 = 0x320;
+const TIMER_INITIAL_COUNT: u32 = 0x380;
+const TIMER_CURRENT_COUNT: u32 = 0x390;
+const TIMER_DIVIDE: u32 = 0x3E0;
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// LAPIC timer ticks per millisecond at a divide of 16, measured once by the BSP
+static LAPIC_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// TSC value at calibration, kernel time starts counting from it
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+static LAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the current CPU's LAPIC timer up for one-shot use
+///
+/// The BSP calibrates the LAPIC timer and TSC against the PIT first, the APs reuse its
+/// measurement since they share the bus clock. The timer is armed once so the CPU enters
+/// the scheduler, after that it only fires when `arm_timer` asks for it.
+pub fn init(lapic_base: usize) {
+    LAPIC_BASE.store(lapic_base, Ordering::Relaxed);
+    let apic = Apic::new();
+
+    if LAPIC_TICKS_PER_MS.load(Ordering::Acquire) == 0 {
+        calibrate(&apic);
+    }
+
+    let mode = if has_tsc_deadline() {
+        LVT_TSC_DEADLINE
+    } else {
+        0 // one-shot
+    };
+
+    unsafe {
+        apic.write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        apic.write(LVT_TIMER, TIMER_VECTOR as u32 | mode);
+    }
+
+    arm_timer(Some(now() + 1));
+}
+
+/// Ticks since the timer was calibrated, read from the TSC so it keeps counting on
+/// CPUs that skip timer interrupts
+pub fn now() -> u64 {
+    let elapsed = unsafe { _rdtsc() }.saturating_sub(BOOT_TSC.load(Ordering::Relaxed));
+
+    elapsed / tsc_per_tick()
+}
+
+/// Microseconds since the timer was calibrated, finer than `now` for tracing
+pub fn now_us() -> u64 {
+    let elapsed = unsafe { _rdtsc() }.saturating_sub(BOOT_TSC.load(Ordering::Relaxed));
+
+    (elapsed as u128 * 1000 / tsc_per_ms().max(1) as u128) as u64
+}
+
+/// Programs the current CPU's next timer interrupt at tick `deadline`, `None` stops it
+pub fn arm_timer(deadline: Option<u64>) {
+    let apic = Apic::new();
+
+    unsafe {
+        if has_tsc_deadline() {
+            let tsc = match deadline {
+                Some(deadline) => BOOT_TSC.load(Ordering::Relaxed) + deadline * tsc_per_tick(),
+                None => 0, // disarms the timer
+            };
+            let mut msr = Msr::new(IA32_TSC_DEADLINE);
+            msr.write(tsc);
+        } else {
+            let count = match deadline {
+                // a deadline in the past still has to fire
+                Some(deadline) => {
+                    let ticks = deadline.saturating_sub(now()).max(1);
+                    let per_tick = LAPIC_TICKS_PER_MS.load(Ordering::Relaxed) * 1000 / HZ;
+                    (ticks * per_tick).min(u32::MAX as u64) as u32
+                }
+                None => 0, // stops the timer
+            };
+            apic.write(TIMER_INITIAL_COUNT, count);
+        }
+    }
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / HZ
+}
+
+/// Rounds up, so a non-zero duration always lasts at least one tick
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * HZ).div_ceil(1000)
+}
+
+/// TSC cycles per millisecond, 0 before the BSP has calibrated
+pub fn tsc_per_ms() -> u64 {
+    TSC_PER_MS.load(Ordering::Relaxed)
+}
+
+fn tsc_per_tick() -> u64 {
+    (tsc_per_ms() * 1000 / HZ).max(1)
+}
+
+fn has_tsc_deadline() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 24) != 0
+}
+
+fn calibrate(apic: &Apic) {
+    let (lapic_ticks, tsc_cycles) = unsafe {
+        apic.write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        apic.write(LVT_TIMER, TIMER_VECTOR as u32 | LVT_MASKED);
+
+        let tsc_start = _rdtsc();
+        apic.write(TIMER_INITIAL_COUNT, u32::MAX);
+        pit_sleep(CALIBRATION_MS);
+        let remaining = apic.read(TIMER_CURRENT_COUNT);
+        let tsc_end = _rdtsc();
+        apic.write(TIMER_INITIAL_COUNT, 0);
+
+        ((u32::MAX - remaining) as u64, tsc_end - tsc_start)
+    };
+
+    BOOT_TSC.store(unsafe { _rdtsc() }, Ordering::Release);
+    TSC_PER_MS.store(tsc_cycles / CALIBRATION_MS, Ordering::Release);
+    LAPIC_TICKS_PER_MS.store(lapic_ticks / CALIBRATION_MS, Ordering::Release);
+    info!(
+        "LAPIC timer: {} ticks/ms, TSC: {} kHz, TSC deadline mode: {}",
+        lapic_ticks / CALIBRATION_MS,
+        tsc_cycles / CALIBRATION_MS,
+        has_tsc_deadline()
+    );
+}
+
+/// Busy waits on PIT channel 2, which can be polled without an interrupt handler
+unsafe fn pit_sleep(ms: u64) {
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    let count = (PIT_FREQUENCY * ms / 1000) as u16;
+
+    // gate low and speaker off while the counter is loaded
+    let value = gate.read() & !0x3;
+    gate.write(value);
+    // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    command.write(0b1011_0000);
+    channel_2.write(count as u8);
+    channel_2.write((count >> 8) as u8);
+    // raising the gate starts the countdown
+    gate.write(value | 0x1);
+
+    // OUT2 goes high once the count hits zero
+    while gate.read() & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+
+    gate.write(value);
+}
+
+/// Raw access to the LAPIC registers the timer needs, in either xAPIC or x2APIC mode
+struct Apic {
+    base: usize,
+    x2apic: bool,
+}
+
+impl Apic {
+    fn new() -> Self {
+        let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+
+        Self {
+            base: LAPIC_BASE.load(Ordering::Relaxed),
+            x2apic: apic_base & X2APIC_ENABLE != 0,
+        }
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        if self.x2apic {
+            Msr::new(X2APIC_MSR_BASE + (register >> 4)).read() as u32
+        } else {
+            core::ptr::read_volatile((self.base + register as usize) as *const u32)
+        }
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        if self.x2apic {
+            let mut msr = Msr::new(X2APIC_MSR_BASE + (register >> 4));
+            msr.write(value as u64);
+        } else {
+            core::ptr::write_volatile((self.base + register as usize) as *mut u32, value);
+        }
+    }
+}
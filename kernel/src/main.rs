@@ -5,9 +5,11 @@
 mod arch;
 mod memory;
 mod output;
+mod random;
 mod sched;
 mod scheme;
 mod syscall;
+mod timer;
 
 use core::arch::asm;
 
@@ -43,10 +45,11 @@ unsafe extern "C" fn kmain() -> ! {
     assert!(BASE_REVISION.is_supported());
     logger::init().unwrap();
     arch::init();
+    random::init();
     interrupts::disable();
-    let reincarnation = Task::reincarnation();
+    let reincarnation = Task::reincarnation().unwrap();
     add_task(reincarnation);
-    let task_manager = Task::task_manager();
+    let task_manager = Task::task_manager().unwrap();
     add_task(task_manager);
     interrupts::enable();
 
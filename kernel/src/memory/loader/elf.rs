@@ -1,16 +1,11 @@
 use goblin::elf::{self, program_header::ProgramHeader, reloc::R_X86_64_RELATIVE, Elf};
-use log::{debug, error, info};
+use log::{debug, error};
 
 use crate::{
-    arch::x86::{
-        cpu::current_pcr,
-        memory::{PMM, VMM},
-    },
     memory::{
         address::VirtualAddress,
         loader::{Loader, LoadingError},
-        paging::{align_down, align_up, PageFlags},
-        physical::PhysicalMemoryManager,
+        paging::{align_down, align_up, phys_to_virt, PageFlags},
         PAGE_SIZE,
     },
     sched::memory::{MemoryAreaType, MemoryDescriptor},
@@ -23,8 +18,35 @@ impl ElfLoader {
         Self
     }
 
+    /// Page flags and region type for a segment, text is read-only and executable, rodata
+    /// read-only and data writable, neither of them executable
+    fn segment_flags(segment: &ProgramHeader) -> Result<(PageFlags, MemoryAreaType), LoadingError> {
+        let flags = PageFlags::USER_ACCESSIBLE | PageFlags::PRESENT;
+        let writable = segment.p_flags & elf::program_header::PF_W != 0;
+        let executable = segment.p_flags & elf::program_header::PF_X != 0;
+
+        match (writable, executable) {
+            (true, true) => {
+                error!(
+                    "Segment at {:#x} is both writable and executable",
+                    segment.p_vaddr
+                );
+                Err(LoadingError::InvalidInput)
+            }
+            (false, true) => Ok((flags, MemoryAreaType::Text)),
+            (true, false) => Ok((
+                flags | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+                MemoryAreaType::Data,
+            )),
+            (false, false) => Ok((flags | PageFlags::NO_EXECUTE, MemoryAreaType::Rodata)),
+        }
+    }
+
+    /// Maps the segment and fills it through the HHDM, the address space it goes into is
+    /// usually not the active one
     fn load_segment(
         &self,
+        memory: &mut MemoryDescriptor,
         base_address: VirtualAddress,
         binary: &[u8],
         segment: &ProgramHeader,
@@ -43,89 +65,96 @@ impl ElfLoader {
         let file_offset = segment.p_vaddr as usize % PAGE_SIZE;
         let total_size = segment.p_memsz as usize + file_offset;
         let mapped_size = align_up(total_size, PAGE_SIZE);
-
-        let phys = PMM
-            .lock()
-            .allocate_contiguous(mapped_size)
-            .map_err(|_| LoadingError::MemoryAllocationError)?;
         let virt = VirtualAddress::new(base_address.as_usize() + vaddr);
-        let flags = PageFlags::USER_ACCESSIBLE | PageFlags::PRESENT | PageFlags::WRITABLE;
+        let (flags, area_type) = Self::segment_flags(segment)?;
 
-        VMM.lock()
-            .map_range(virt, phys, mapped_size, flags)
+        memory
+            .reserve(virt, mapped_size, flags, area_type)
             .map_err(|_| LoadingError::MappingError)?;
+
+        // only the pages with file contents are backed now, the rest of the BSS is zeroed
+        // on demand
+        if segment.p_filesz == 0 {
+            return Ok(());
+        }
+
+        let file_size = align_up(file_offset + segment.p_filesz as usize, PAGE_SIZE);
+        let phys = memory
+            .populate(virt, file_size)
+            .map_err(|_| LoadingError::MemoryAllocationError)?;
+        let dest = (phys_to_virt(phys.as_usize()) + file_offset) as *mut u8;
+
         unsafe {
             let src = binary.as_ptr().offset(segment.p_offset as isize);
-            let dest = (base_address.as_usize() + segment.p_vaddr as usize) as *mut u8;
             debug!("Copying {} bytes to {:p}", segment.p_filesz, dest);
+            core::ptr::write_bytes(phys_to_virt(phys.as_usize()) as *mut u8, 0, file_offset);
             core::ptr::copy_nonoverlapping(src, dest, segment.p_filesz as usize);
-        }
 
-        if segment.p_memsz > segment.p_filesz {
-            let bss_start =
-                base_address.as_usize() + segment.p_vaddr as usize + segment.p_filesz as usize;
-            let bss_size = (segment.p_memsz - segment.p_filesz) as usize;
-
-            debug!("Zeroing BSS at {:#x?} ({} bytes)", bss_start, bss_size);
-            unsafe {
-                core::ptr::write_bytes(bss_start as *mut u8, 0, bss_size);
-            }
+            // the frames are fresh, so the rest of the last one has to be cleared
+            let bss_size = file_size - file_offset - segment.p_filesz as usize;
+            debug!("Zeroing BSS ({} bytes)", bss_size);
+            core::ptr::write_bytes(dest.add(segment.p_filesz as usize), 0, bss_size);
         }
 
         Ok(())
     }
 
-    fn apply_relocations(&self, elf: &Elf, base_address: usize) {
+    fn apply_relocations(
+        &self,
+        memory: &mut MemoryDescriptor,
+        elf: &Elf,
+        base_address: usize,
+    ) -> Result<(), LoadingError> {
         for rela in &elf.dynrelas {
             if rela.r_type == R_X86_64_RELATIVE {
                 debug!("Applying relocation: {:#x?}", rela);
-                let reloc_addr = base_address + rela.r_offset as usize;
+                let reloc_addr = VirtualAddress::new(base_address + rela.r_offset as usize);
                 let value = base_address + rela.r_addend.unwrap_or(0) as usize;
+
+                // a relocation in the BSS lands on a page nothing backs yet
+                if memory.translate(reloc_addr).is_none() {
+                    memory
+                        .handle_fault(reloc_addr)
+                        .map_err(|_| LoadingError::InvalidInput)?;
+                }
+
+                // file pages are physically contiguous, so the word can't straddle two frames
+                let phys = memory
+                    .translate(reloc_addr)
+                    .ok_or(LoadingError::InvalidInput)?;
                 unsafe {
-                    core::ptr::write_unaligned(reloc_addr as *mut usize, value);
+                    core::ptr::write_unaligned(phys_to_virt(phys.as_usize()) as *mut usize, value);
                 }
             } else {
                 error!("Unsupported relocation type: {}", rela.r_type);
             }
         }
+
+        Ok(())
     }
 }
 
 impl Loader for ElfLoader {
     fn load(
         &self,
+        memory: &mut MemoryDescriptor,
         base_address: VirtualAddress,
         binary: &[u8],
-    ) -> Result<(MemoryDescriptor, VirtualAddress), LoadingError> {
+    ) -> Result<VirtualAddress, LoadingError> {
         let elf = Elf::parse(binary).map_err(|_| LoadingError::ParseError)?;
-        let mut memory_descriptor = MemoryDescriptor::new();
 
         for ph in elf.program_headers.iter() {
             if ph.p_type != elf::program_header::PT_LOAD {
                 continue;
             }
 
-            let start = ph.p_vaddr;
-            let end = start + ph.p_memsz;
-            let flags = PageFlags::USER_ACCESSIBLE | PageFlags::PRESENT | PageFlags::WRITABLE;
-
-            self.load_segment(base_address, binary, &ph)?;
-
-            let area_type = if ph.p_flags & elf::program_header::PF_X != 0 {
-                MemoryAreaType::Text
-            } else if ph.p_flags & elf::program_header::PF_W != 0 {
-                MemoryAreaType::Data
-            } else {
-                MemoryAreaType::Heap
-            };
-
-            memory_descriptor.add_region(start, end, flags, area_type);
+            self.load_segment(memory, base_address, binary, &ph)?;
         }
 
-        self.apply_relocations(&elf, base_address.as_usize());
+        self.apply_relocations(memory, &elf, base_address.as_usize())?;
         let entry = base_address.offset(elf.entry as usize);
-        memory_descriptor.entrypoint = entry.as_u64();
+        memory.entrypoint = entry.as_u64();
 
-        Ok((memory_descriptor, entry))
+        Ok(entry)
     }
 }
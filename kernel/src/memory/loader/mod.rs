@@ -9,11 +9,14 @@ use super::address::VirtualAddress;
 pub mod elf;
 
 pub trait Loader {
+    /// Maps the binary into `memory` at `base_address` and returns its entry point, the
+    /// address space doesn't have to be the active one
     fn load(
         &self,
+        memory: &mut MemoryDescriptor,
         base_address: VirtualAddress,
         binary: &[u8],
-    ) -> Result<(MemoryDescriptor, VirtualAddress), LoadingError>;
+    ) -> Result<VirtualAddress, LoadingError>;
 }
 
 #[derive(Debug)]
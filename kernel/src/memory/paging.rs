@@ -32,10 +32,18 @@ bitflags! {
         const DIRTY        = 1 << 6;
         const HUGE_PAGE    = 1 << 7;
         const GLOBAL       = 1 << 8;
+        /// Read-only page shared with a forked task, copied on the first write
+        const COPY_ON_WRITE = 1 << 9;
         const NO_EXECUTE   = 1 << 63;
     }
 }
 
+impl PageFlags {
+    /// Write-combining caching, for framebuffers, `init_cpu` sets the PAT entry that
+    /// `WRITE_THROUGH` picks to it
+    pub const WRITE_COMBINING: PageFlags = PageFlags::WRITE_THROUGH;
+}
+
 #[derive(Debug)]
 pub enum MapError {
     AlreadyMapped,
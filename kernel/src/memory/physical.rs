@@ -1,23 +1,39 @@
 use core::{error::Error, fmt::Display};
 
+use alloc::collections::btree_map::BTreeMap;
 use bitmap_allocator::{BitAlloc, BitAlloc256M};
 use spinning_top::Spinlock;
 
+use crate::sched::pid::Pid;
+
 use super::address::PhysicalAddress;
 
 pub static FRAME_ALLOCATOR: Spinlock<BitAlloc256M> = Spinlock::new(BitAlloc256M::DEFAULT);
 
 pub trait PhysicalMemoryManager {
     /// Allocate a single physical frame
-    fn allocate(&mut self) -> Result<PhysicalAddress, FrameAllocationError>;
+    fn allocate(&mut self, owner: FrameOwner) -> Result<PhysicalAddress, FrameAllocationError>;
 
     /// Alocate a block of contiguous frames returning the address of the start of the block
-    fn allocate_contiguous(&mut self, size: usize)
-        -> Result<PhysicalAddress, FrameAllocationError>;
+    fn allocate_contiguous(
+        &mut self,
+        size: usize,
+        owner: FrameOwner,
+    ) -> Result<PhysicalAddress, FrameAllocationError>;
 
-    /// Free a previously allocated physical frame
+    /// Drop a reference to a previously allocated physical frame, freeing it once none are
+    /// left
+    ///
+    /// Freeing a frame that isn't allocated or is reserved is logged and counted instead.
     fn free(&mut self, frame: PhysicalAddress);
 
+    /// Take another reference to an allocated frame, for pages shared between address
+    /// spaces
+    fn share(&mut self, frame: PhysicalAddress);
+
+    /// References to an allocated frame, 1 unless it was shared
+    fn ref_count(&self, frame: PhysicalAddress) -> usize;
+
     /// Check if a specific frame is available
     fn is_frame_free(&self, frame: PhysicalAddress) -> bool;
 
@@ -29,6 +45,53 @@ pub trait PhysicalMemoryManager {
 
     /// Reserve a specific frame range (for kernel, hardware, etc.)
     fn reserve_frame_range(&mut self, start: PhysicalAddress, end: PhysicalAddress);
+
+    /// Who an allocated frame belongs to
+    fn owner(&self, frame: PhysicalAddress) -> Option<FrameOwner>;
+
+    /// Hands an allocated frame to `owner`
+    fn set_owner(&mut self, frame: PhysicalAddress, owner: FrameOwner);
+
+    /// Frames held by each owner, reserved ones included
+    fn usage(&self) -> BTreeMap<FrameOwner, usize>;
+
+    /// Frees of frames that weren't allocated or were reserved
+    fn bad_frees(&self) -> usize;
+}
+
+/// What a frame was allocated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FrameOwner {
+    /// Kernel stacks and anything else the kernel keeps for itself
+    Kernel,
+    KernelHeap,
+    PageTable,
+    /// Pages of the task's address space
+    Task(Pid),
+    /// Pages of a shared memory region
+    Shared,
+    /// Buffers handed to devices
+    Dma,
+    /// MMIO, ACPI tables and boot modules, never allocated or freed
+    Reserved,
+    /// Pages of a task that exited while another task still mapped them, they go away as
+    /// the others unmap them, so a count that keeps growing is a leak
+    Orphaned,
+}
+
+impl Display for FrameOwner {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameOwner::Kernel => write!(f, "kernel"),
+            FrameOwner::KernelHeap => write!(f, "kernel-heap"),
+            FrameOwner::PageTable => write!(f, "page-table"),
+            FrameOwner::Task(pid) => write!(f, "task-{}", pid),
+            FrameOwner::Shared => write!(f, "shared"),
+            FrameOwner::Dma => write!(f, "dma"),
+            FrameOwner::Reserved => write!(f, "reserved"),
+            FrameOwner::Orphaned => write!(f, "orphaned"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
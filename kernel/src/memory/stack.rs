@@ -3,9 +3,15 @@ use log::{debug, info};
 use crate::{
     arch::x86::{
         cpu::current_pcr,
-        memory::{PMM, VMM},
+        memory::{release_range, PMM, VMM},
     },
-    memory::{address::VirtualAddress, paging::PageFlags, physical::PhysicalMemoryManager},
+    memory::{
+        address::VirtualAddress,
+        paging::{MapError, PageFlags},
+        physical::{FrameOwner, PhysicalMemoryManager},
+        PAGE_SIZE,
+    },
+    sched::memory::{MemoryAreaType, MemoryDescriptor},
 };
 
 #[derive(Debug)]
@@ -16,18 +22,22 @@ pub struct Stack {
 }
 
 impl Stack {
+    /// Kernel stack, mapped in the kernel half every address space shares
     pub fn new(bottom: VirtualAddress, size: usize) -> Self {
         debug!(
             "Creating stack starting at {:#x?} with size {:#x}",
             bottom, size
         );
-        let bottom_phys = PMM.lock().allocate_contiguous(size).unwrap();
+        let bottom_phys = PMM
+            .lock()
+            .allocate_contiguous(size, FrameOwner::Kernel)
+            .unwrap();
         VMM.lock()
             .map_range(
                 bottom,
                 bottom_phys,
                 size,
-                PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER_ACCESSIBLE,
+                PageFlags::PRESENT | PageFlags::WRITABLE,
             )
             .unwrap();
 
@@ -38,6 +48,46 @@ impl Stack {
         }
     }
 
+    /// User stack, reserved as a region of the task's address space and backed as it grows
+    ///
+    /// The page below it is left out of every region, so an overflow faults on it.
+    pub fn user(
+        memory: &mut MemoryDescriptor,
+        bottom: VirtualAddress,
+        size: usize,
+    ) -> Result<Self, MapError> {
+        debug!(
+            "Creating user stack starting at {:#x?} with size {:#x}",
+            bottom, size
+        );
+        memory.reserve(
+            bottom,
+            size,
+            PageFlags::PRESENT
+                | PageFlags::WRITABLE
+                | PageFlags::USER_ACCESSIBLE
+                | PageFlags::NO_EXECUTE,
+            MemoryAreaType::Stack,
+        )?;
+        memory.start_stack = (bottom.as_usize() + size) as u64;
+        memory.stack = memory.start_stack;
+
+        Ok(Self {
+            bottom,
+            size,
+            len: 0,
+        })
+    }
+
+    /// The same user stack in a forked task, whose address space has a copy of it
+    pub fn fork(&self) -> Self {
+        Self {
+            bottom: self.bottom,
+            size: self.size,
+            len: self.len,
+        }
+    }
+
     pub const fn empty() -> Self {
         Self {
             bottom: VirtualAddress::new(0),
@@ -46,6 +96,15 @@ impl Stack {
         }
     }
 
+    /// Whether `address` is in the unmapped page right below the stack, where an overflow
+    /// lands
+    pub fn is_guard(&self, address: VirtualAddress) -> bool {
+        let bottom = self.bottom.as_usize();
+        let address = address.as_usize();
+
+        self.size != 0 && address < bottom && address >= bottom.saturating_sub(PAGE_SIZE)
+    }
+
     pub fn top(&self) -> VirtualAddress {
         // stacks grow downwards, so the top is the bottom address + size - len
         VirtualAddress::new(self.bottom.as_usize() + self.size - self.len)
@@ -57,6 +116,15 @@ impl Stack {
         debug!("Stack top set to {:#x?}", top);
     }
 
+    /// Unmaps a kernel stack and frees its frames, returns how many were freed
+    pub fn release(&mut self) -> usize {
+        let frames = release_range(self.bottom, self.size);
+        self.size = 0;
+        self.len = 0;
+
+        frames
+    }
+
     /// Resets the stack to its original state
     pub fn restart(&mut self) {
         debug!("Restarting stack");
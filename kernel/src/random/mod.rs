@@ -0,0 +1,110 @@
+use log::{info, warn};
+use spinning_top::Spinlock;
+
+use crate::arch::x86::random::{probe, rdrand, rdseed, timestamp};
+
+pub static POOL: Spinlock<EntropyPool> = Spinlock::new(EntropyPool::new());
+
+const BOOT_SEED_ROUNDS: usize = 16;
+
+/// Kernel entropy pool
+///
+/// Inputs are folded into a xoshiro256** state, which is also used to produce the output.
+/// RDRAND is mixed in again on every output when the CPU has it.
+pub struct EntropyPool {
+    state: [u64; 4],
+    inputs: u64,
+}
+
+impl EntropyPool {
+    pub const fn new() -> Self {
+        Self {
+            state: [
+                0x6a09_e667_f3bc_c908,
+                0xbb67_ae85_84ca_a73b,
+                0x3c6e_f372_fe94_f82b,
+                0xa54f_f53a_5f1d_36f1,
+            ],
+            inputs: 0,
+        }
+    }
+
+    /// Mixes a value into the pool
+    pub fn add_entropy(&mut self, value: u64) {
+        let index = (self.inputs % 4) as usize;
+        self.state[index] ^= splitmix64(value ^ self.inputs);
+        self.inputs = self.inputs.wrapping_add(1);
+        self.step();
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        if let Some(value) = rdrand() {
+            self.add_entropy(value);
+        }
+
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        self.step();
+
+        result
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_ne_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn step(&mut self) {
+        let t = self.state[1] << 17;
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+    }
+}
+
+/// Seeds the pool from the hardware sources available at boot
+pub fn init() {
+    probe();
+
+    let mut pool = POOL.lock();
+    let mut hardware = false;
+
+    for _ in 0..BOOT_SEED_ROUNDS {
+        if let Some(value) = rdseed().or_else(rdrand) {
+            pool.add_entropy(value);
+            hardware = true;
+        }
+
+        pool.add_entropy(timestamp());
+    }
+
+    if hardware {
+        info!("Entropy pool seeded from RDSEED/RDRAND and TSC");
+    } else {
+        warn!("No hardware RNG available, entropy pool seeded from TSC only");
+    }
+}
+
+/// Mixes the current timestamp into the pool, called from interrupt handlers so the
+/// arrival jitter of interrupts feeds the pool
+pub fn add_interrupt_entropy() {
+    // never spin inside an interrupt, the lock might be held by the code we interrupted
+    if let Some(mut pool) = POOL.try_lock() {
+        pool.add_entropy(timestamp());
+    }
+}
+
+pub fn fill(buf: &mut [u8]) {
+    POOL.lock().fill(buf);
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,275 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use libjon::errno::{EINVAL, ESRCH};
+use spinning_top::Spinlock;
+
+use crate::{
+    arch::x86::timer::{ms_to_ticks, ticks_to_ms},
+    timer::{add_timer, TimerAction},
+};
+
+use super::{
+    pid::Pid,
+    scheduler::{get_task_mut, get_tasks},
+    task::{SchedClass, Task},
+};
+
+static CGROUPS: Spinlock<BTreeMap<usize, CGroup>> = Spinlock::new(BTreeMap::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Period a new group starts with, in milliseconds
+const DEFAULT_PERIOD_MS: u64 = 100;
+
+/// Set of tasks sharing a CPU bandwidth quota
+///
+/// Member tasks may run for `quota` ticks of CPU time, summed over every CPU, in each
+/// `period`. Once that is used up they are parked until the period ends.
+#[derive(Debug)]
+struct CGroup {
+    name: String,
+    /// `None` leaves the group uncapped
+    quota: Option<u64>,
+    period: u64,
+    /// CPU time used in the current period
+    usage: u64,
+    period_start: u64,
+    /// End of the period the group ran out of quota in
+    throttled_until: Option<u64>,
+    /// Members pulled off their run queue while throttled
+    parked: Vec<Pid>,
+    /// Times the group ran out of quota
+    throttled_count: u64,
+}
+
+impl CGroup {
+    fn new(name: &str, now: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            quota: None,
+            period: ms_to_ticks(DEFAULT_PERIOD_MS),
+            usage: 0,
+            period_start: now,
+            throttled_until: None,
+            parked: Vec::new(),
+            throttled_count: 0,
+        }
+    }
+
+    /// Starts a new period if the current one is over
+    fn refresh(&mut self, now: u64) {
+        if now < self.period_start + self.period {
+            return;
+        }
+
+        let periods = (now - self.period_start) / self.period;
+        self.period_start += periods * self.period;
+        self.usage = 0;
+    }
+
+    fn throttled(&self, now: u64) -> bool {
+        self.throttled_until.is_some_and(|until| now < until)
+    }
+}
+
+pub fn find(name: &str) -> Option<usize> {
+    CGROUPS
+        .lock()
+        .iter()
+        .find(|(_, group)| group.name == name)
+        .map(|(id, _)| *id)
+}
+
+/// Creates an uncapped group, names must be unique
+pub fn create(name: &str, now: u64) -> Result<usize, i32> {
+    if name.is_empty() || name.contains(char::is_whitespace) || find(name).is_some() {
+        return Err(EINVAL);
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    CGROUPS.lock().insert(id, CGroup::new(name, now));
+
+    Ok(id)
+}
+
+/// Caps the group at `quota_ms` of CPU time every `period_ms`, a `quota_ms` of `None`
+/// lifts the cap
+pub fn set_quota(id: usize, quota_ms: Option<u64>, period_ms: u64, now: u64) -> Result<(), i32> {
+    if period_ms == 0 || quota_ms == Some(0) {
+        return Err(EINVAL);
+    }
+
+    let mut cgroups = CGROUPS.lock();
+    let group = cgroups.get_mut(&id).ok_or(EINVAL)?;
+    group.quota = quota_ms.map(ms_to_ticks);
+    group.period = ms_to_ticks(period_ms);
+    group.period_start = now;
+    group.usage = 0;
+
+    Ok(())
+}
+
+pub fn period_ms(id: usize) -> Option<u64> {
+    CGROUPS
+        .lock()
+        .get(&id)
+        .map(|group| ticks_to_ms(group.period))
+}
+
+/// Moves a task into the group, `None` takes it out of any group
+///
+/// Returns true when the task was parked by its old group, the caller has to make it
+/// runnable again.
+pub fn attach(id: Option<usize>, pid: Pid) -> Result<bool, i32> {
+    if id.is_some_and(|id| !CGROUPS.lock().contains_key(&id)) {
+        return Err(EINVAL);
+    }
+
+    let task = get_task_mut(pid).ok_or(ESRCH)?;
+    let parked = detach(pid);
+    task.cgroup = id;
+
+    Ok(parked)
+}
+
+/// Takes the task off every group's parked list, returns whether it was on one
+pub fn detach(pid: Pid) -> bool {
+    let mut parked = false;
+
+    for group in CGROUPS.lock().values_mut() {
+        let len = group.parked.len();
+        group.parked.retain(|&p| p != pid);
+        parked |= group.parked.len() != len;
+    }
+
+    parked
+}
+
+/// Charges CPU time to the task's group, the first time the quota runs out in a period an
+/// `Unthrottle` timer is armed on the current CPU for the end of it
+pub fn charge(task: &Task, elapsed: u64, now: u64) {
+    let Some(id) = task.cgroup else {
+        return;
+    };
+    let mut cgroups = CGROUPS.lock();
+    let Some(group) = cgroups.get_mut(&id) else {
+        return;
+    };
+    group.refresh(now);
+    group.usage += elapsed;
+
+    let Some(quota) = group.quota else {
+        return;
+    };
+
+    if group.usage >= quota && !group.throttled(now) {
+        let until = group.period_start + group.period;
+        group.throttled_until = Some(until);
+        group.throttled_count += 1;
+        drop(cgroups);
+
+        add_timer(until, TimerAction::Unthrottle(id));
+    }
+}
+
+/// Holds the task until its group gets quota again if the group used up its quota, returns
+/// whether it did
+///
+/// Checked and parked under one lock, so an `unthrottle` can't slip in between and leave
+/// the task parked. Deadline tasks are never throttled.
+pub fn park_if_throttled(task: &Task, now: u64) -> bool {
+    let Some(id) = task.cgroup else {
+        return false;
+    };
+
+    if task.class != SchedClass::Normal {
+        return false;
+    }
+
+    let mut cgroups = CGROUPS.lock();
+    let Some(group) = cgroups.get_mut(&id) else {
+        return false;
+    };
+
+    if !group.throttled(now) {
+        return false;
+    }
+
+    if !group.parked.contains(&task.pid) {
+        group.parked.push(task.pid);
+    }
+
+    true
+}
+
+/// Quota the task's group has left in this period, `None` when it is uncapped
+pub fn remaining(task: &Task, now: u64) -> Option<u64> {
+    let id = task.cgroup?;
+    let mut cgroups = CGROUPS.lock();
+    let group = cgroups.get_mut(&id)?;
+    group.refresh(now);
+
+    group.quota.map(|quota| quota.saturating_sub(group.usage))
+}
+
+/// Ends the group's throttling and returns the tasks that were parked
+pub fn unthrottle(id: usize, now: u64) -> Vec<Pid> {
+    let mut cgroups = CGROUPS.lock();
+    let Some(group) = cgroups.get_mut(&id) else {
+        return Vec::new();
+    };
+    group.refresh(now);
+    group.throttled_until = None;
+
+    core::mem::take(&mut group.parked)
+}
+
+/// Names of every group, one per line
+pub fn render_list() -> String {
+    let mut out = String::new();
+
+    for group in CGROUPS.lock().values() {
+        writeln!(out, "{}", group.name).unwrap();
+    }
+
+    out
+}
+
+pub fn render(id: usize, now: u64) -> String {
+    let mut out = String::new();
+    let members: Vec<Pid> = get_tasks()
+        .iter()
+        .filter(|task| task.cgroup == Some(id))
+        .map(|task| task.pid)
+        .collect();
+    let mut cgroups = CGROUPS.lock();
+    let Some(group) = cgroups.get_mut(&id) else {
+        return out;
+    };
+    group.refresh(now);
+
+    match group.quota {
+        Some(quota) => writeln!(out, "quota: {} ms", ticks_to_ms(quota)).unwrap(),
+        None => writeln!(out, "quota: max").unwrap(),
+    }
+    writeln!(out, "period: {} ms", ticks_to_ms(group.period)).unwrap();
+    writeln!(out, "usage: {} ms", ticks_to_ms(group.usage)).unwrap();
+    writeln!(out, "throttled: {}", group.throttled(now)).unwrap();
+    writeln!(out, "throttled_count: {}", group.throttled_count).unwrap();
+    write!(out, "tasks:").unwrap();
+
+    for pid in members {
+        let parked = group.parked.contains(&pid);
+        write!(out, " {}{}", pid, if parked { "*" } else { "" }).unwrap();
+    }
+    writeln!(out).unwrap();
+
+    out
+}
@@ -16,8 +16,10 @@ lazy_static! {
 /// A file descriptor
 #[derive(Debug, Clone)]
 pub struct FileDescriptor {
-    /// The file descriptor number
+    /// The scheme's handle for the descriptor, unique across tasks
     pub id: FileDescriptorId,
+    /// The number the owning task knows the descriptor by, given out by `Task::add_file`
+    pub number: usize,
     /// The file descriptor offset, used for seeking
     pub offset: usize,
     /// The scheme that the descriptor belongs to
@@ -31,6 +33,7 @@ impl FileDescriptor {
     pub fn new(scheme: SchemeId, flags: FileDescriptorFlags) -> Self {
         Self {
             id: FileDescriptorId(FD_ALLOCATOR.lock().alloc().unwrap()),
+            number: 0,
             offset: 0,
             scheme,
             flags,
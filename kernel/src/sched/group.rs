@@ -0,0 +1,77 @@
+use alloc::vec::Vec;
+use libjon::errno::{EPERM, ESRCH};
+
+use super::{
+    pid::Pid,
+    scheduler::{current_pid, get_task, get_task_mut, get_tasks},
+    task::{State, Task},
+};
+
+/// Moves `pid` into the process group `pgid`, 0 for either means the caller
+///
+/// The caller can only move itself or its children, within its own session, and only into a
+/// new group named after the task or into a group that already exists in that session.
+pub fn set_pgid(pid: usize, pgid: usize) -> Result<(), i32> {
+    let caller = get_task(current_pid().ok_or(ESRCH)?).ok_or(ESRCH)?;
+    let pid = match pid {
+        0 => caller.pid,
+        pid => Pid::new(pid),
+    };
+    let pgid = match pgid {
+        0 => pid,
+        pgid => Pid::new(pgid),
+    };
+    let task = get_task_mut(pid).ok_or(ESRCH)?;
+
+    if task.pid != caller.pid && task.parent != Some(caller.pid) {
+        return Err(ESRCH);
+    }
+
+    // a session leader can't leave its session's group
+    if task.sid != caller.sid || task.sid == task.pid {
+        return Err(EPERM);
+    }
+
+    if pgid != pid && members(pgid).all(|member| member.sid != task.sid) {
+        return Err(EPERM);
+    }
+
+    task.pgid = pgid;
+
+    Ok(())
+}
+
+pub fn get_pgid(pid: usize) -> Result<Pid, i32> {
+    let pid = match pid {
+        0 => current_pid().ok_or(ESRCH)?,
+        pid => Pid::new(pid),
+    };
+
+    get_task(pid).map(|task| task.pgid).ok_or(ESRCH)
+}
+
+/// Starts a new session, with the caller as the leader of it and of its only group
+pub fn set_sid() -> Result<Pid, i32> {
+    let task = get_task_mut(current_pid().ok_or(ESRCH)?).ok_or(ESRCH)?;
+
+    // otherwise the group would end up split across two sessions
+    if members(task.pid).next().is_some() {
+        return Err(EPERM);
+    }
+
+    task.sid = task.pid;
+    task.pgid = task.pid;
+
+    Ok(task.sid)
+}
+
+/// Live tasks in the process group `pgid`
+pub fn group_members(pgid: Pid) -> Vec<Pid> {
+    members(pgid).map(|task| task.pid).collect()
+}
+
+fn members(pgid: Pid) -> impl Iterator<Item = &'static Task> {
+    get_tasks()
+        .into_iter()
+        .filter(move |task| task.pgid == pgid && task.state != State::Stopped)
+}
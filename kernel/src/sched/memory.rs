@@ -1,9 +1,32 @@
 use alloc::vec::Vec;
+use log::debug;
 
-use crate::memory::{address::VirtualAddress, paging::PageFlags};
+use crate::{
+    arch::x86::memory::{paging::X86VirtualMemoryManager, PMM, VMM},
+    memory::{
+        address::{PhysicalAddress, VirtualAddress},
+        paging::{align_down, align_up, phys_to_virt, MapError, PageFlags, VirtualMemoryManager},
+        physical::{FrameOwner, PhysicalMemoryManager},
+        PAGE_SIZE,
+    },
+};
 
-#[derive(Debug, Clone)]
+/// Every task's binary is loaded at the same address, in its own address space
+pub const BINARY_START: usize = 0x400000;
+pub const HEAP_START: usize = 0x6000_0000;
+pub const HEAP_SIZE: usize = 10 * 1024 * 1024;
+/// Where the first mapping of a scheme's pages goes, see `map_shared`
+pub const MMAP_START: usize = 0x0000_6000_0000_0000;
+/// Bottom of the user stack
+pub const USER_STACK_START: usize = 0x0000700000000000;
+
+/// A task's address space and what is mapped in it
+#[derive(Debug)]
 pub struct MemoryDescriptor {
+    /// `None` for kernel tasks, they run on the kernel's page table
+    page_table: Option<X86VirtualMemoryManager>,
+    /// Who the frames backing the regions are allocated to
+    owner: FrameOwner,
     pub regions: Vec<VirtualMemoryArea>,
     pub start_brk: u64,
     pub brk: u64,
@@ -23,14 +46,31 @@ struct VirtualMemoryArea {
 #[derive(Debug, Clone, Copy)]
 pub enum MemoryAreaType {
     Text,
+    Rodata,
     Data,
     Heap,
     Stack,
+    /// Pages mapped from a scheme, shared with whoever else mapped them
+    Shared,
 }
 
 impl MemoryDescriptor {
-    pub fn new() -> Self {
+    /// An empty user address space, whose frames are allocated to `owner`
+    pub fn new(owner: FrameOwner) -> Result<Self, MapError> {
+        let page_table = X86VirtualMemoryManager::new_user(&VMM.lock())?;
+
+        Ok(Self {
+            page_table: Some(page_table),
+            owner,
+            ..Self::kernel()
+        })
+    }
+
+    /// For tasks that never leave the kernel
+    pub fn kernel() -> Self {
         Self {
+            page_table: None,
+            owner: FrameOwner::Kernel,
             regions: Vec::new(),
             start_brk: 0,
             brk: 0,
@@ -40,6 +80,252 @@ impl MemoryDescriptor {
         }
     }
 
+    /// Records `size` bytes at `start` as a region without backing it, its pages get a
+    /// frame when they are first touched, see `handle_fault`
+    pub fn reserve(
+        &mut self,
+        start: VirtualAddress,
+        size: usize,
+        flags: PageFlags,
+        area_type: MemoryAreaType,
+    ) -> Result<(), MapError> {
+        if self.page_table.is_none() {
+            return Err(MapError::InvalidAddress);
+        }
+
+        let start = start.align_down(PAGE_SIZE).as_u64();
+        let end = start + align_up(size, PAGE_SIZE) as u64;
+
+        if self
+            .regions
+            .iter()
+            .any(|region| region.start < end && start < region.end)
+        {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        self.add_region(start, end, flags, area_type);
+
+        Ok(())
+    }
+
+    /// Backs `size` bytes at `start`, inside a reserved region, with new frames right away
+    ///
+    /// The frames are contiguous, the address of the first one is returned so the kernel
+    /// can fill them through the HHDM without switching address spaces.
+    pub fn populate(
+        &mut self,
+        start: VirtualAddress,
+        size: usize,
+    ) -> Result<PhysicalAddress, MapError> {
+        let start = start.align_down(PAGE_SIZE);
+        let size = align_up(size, PAGE_SIZE);
+        let flags = self
+            .find_region(start)
+            .filter(|region| start.as_u64() + size as u64 <= region.end)
+            .ok_or(MapError::InvalidAddress)?
+            .flags;
+        let page_table = self.page_table.as_mut().ok_or(MapError::InvalidAddress)?;
+        let phys = PMM
+            .lock()
+            .allocate_contiguous(size, self.owner)
+            .map_err(|_| MapError::NoPhysicalMemory)?;
+
+        if let Err(e) = page_table.map_range(start, phys, size, flags) {
+            page_table.unmap_range(start, size);
+            let mut pmm = PMM.lock();
+
+            for offset in (0..size).step_by(PAGE_SIZE) {
+                pmm.free(phys.offset(offset));
+            }
+
+            return Err(e);
+        }
+
+        Ok(phys)
+    }
+
+    /// Backs the page holding `address` with a zeroed frame, for a task that touched a
+    /// reserved page for the first time
+    pub fn handle_fault(&mut self, address: VirtualAddress) -> Result<(), MapError> {
+        let page = address.align_down(PAGE_SIZE);
+        let flags = self
+            .find_region(page)
+            .ok_or(MapError::InvalidAddress)?
+            .flags;
+        let page_table = self.page_table.as_mut().ok_or(MapError::InvalidAddress)?;
+
+        if page_table.is_mapped(page) {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        let frame = PMM
+            .lock()
+            .allocate(self.owner)
+            .map_err(|_| MapError::NoPhysicalMemory)?;
+        unsafe { core::ptr::write_bytes(phys_to_virt(frame.as_usize()) as *mut u8, 0, PAGE_SIZE) };
+
+        if let Err(e) = page_table.map(page, frame, flags) {
+            PMM.lock().free(frame);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Gives the task its own copy of a page it shares with a forked task, for the first
+    /// write to it
+    ///
+    /// The last one to write gets the frame back writable without a copy.
+    pub fn copy_on_write(&mut self, address: VirtualAddress) -> Result<(), MapError> {
+        let page = address.align_down(PAGE_SIZE);
+        let flags = self
+            .find_region(page)
+            .ok_or(MapError::InvalidAddress)?
+            .flags;
+        let page_table = self.page_table.as_mut().ok_or(MapError::InvalidAddress)?;
+
+        if !page_table.is_copy_on_write(page) {
+            return Err(MapError::InvalidAddress);
+        }
+
+        let frame = page_table
+            .get_physical_address(page)
+            .ok_or(MapError::InvalidAddress)?;
+
+        if PMM.lock().ref_count(frame) == 1 {
+            return page_table.update_flags(page, flags);
+        }
+
+        let copy = PMM
+            .lock()
+            .allocate(self.owner)
+            .map_err(|_| MapError::NoPhysicalMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(frame.as_usize()) as *const u8,
+                phys_to_virt(copy.as_usize()) as *mut u8,
+                PAGE_SIZE,
+            );
+        }
+
+        page_table
+            .unmap(page)
+            .map_err(|_| MapError::InvalidAddress)?;
+        page_table.map(page, copy, flags)?;
+        PMM.lock().free(frame);
+
+        Ok(())
+    }
+
+    /// Copy of this address space for a forked task
+    ///
+    /// Both sides share every backed page, writable ones turn read-only and are copied by
+    /// whichever side writes to them first, see `copy_on_write`. Must run on this address
+    /// space, so the pages turned read-only are flushed from the TLB.
+    pub fn fork(&mut self, owner: FrameOwner) -> Result<Self, MapError> {
+        let page_table = self.page_table.as_mut().ok_or(MapError::InvalidAddress)?;
+        let mut child = Self::new(owner)?;
+        child.regions = self.regions.clone();
+        child.start_brk = self.start_brk;
+        child.brk = self.brk;
+        child.start_stack = self.start_stack;
+        child.stack = self.stack;
+        child.entrypoint = self.entrypoint;
+
+        for region in self.regions.iter() {
+            // shared pages stay shared, writes to them are seen by both sides
+            let flags = if region.flags.contains(PageFlags::WRITABLE)
+                && !matches!(region.area_type, MemoryAreaType::Shared)
+            {
+                (region.flags - PageFlags::WRITABLE) | PageFlags::COPY_ON_WRITE
+            } else {
+                region.flags
+            };
+
+            for address in (region.start..region.end).step_by(PAGE_SIZE) {
+                let page = VirtualAddress::new(address as usize);
+                let Some(frame) = page_table.get_physical_address(page) else {
+                    continue;
+                };
+
+                let mapped = child.page_table.as_mut().unwrap().map(page, frame, flags);
+
+                if let Err(e) = mapped {
+                    child.release();
+                    return Err(e);
+                }
+
+                PMM.lock().share(frame);
+
+                if flags != region.flags {
+                    if let Err(e) = page_table.update_flags(page, flags) {
+                        child.release();
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        Ok(child)
+    }
+
+    /// Maps `frames` one after another after the last shared mapping, returns where they
+    /// start
+    ///
+    /// The mapping holds a reference to each frame which `unmap_shared` or `release` drops.
+    /// On error nothing is left mapped and the references are still the caller's.
+    pub fn map_shared(
+        &mut self,
+        frames: &[PhysicalAddress],
+        flags: PageFlags,
+    ) -> Result<VirtualAddress, MapError> {
+        let page_table = self.page_table.as_mut().ok_or(MapError::InvalidAddress)?;
+        let start = self
+            .regions
+            .iter()
+            .filter(|region| matches!(region.area_type, MemoryAreaType::Shared))
+            .map(|region| region.end)
+            .max()
+            .unwrap_or(MMAP_START as u64);
+        let size = frames.len() * PAGE_SIZE;
+
+        for (i, frame) in frames.iter().enumerate() {
+            let page = VirtualAddress::new(start as usize + i * PAGE_SIZE);
+
+            if let Err(e) = page_table.map(page, *frame, flags) {
+                page_table.unmap_range(VirtualAddress::new(start as usize), size);
+                return Err(e);
+            }
+        }
+
+        self.add_region(start, start + size as u64, flags, MemoryAreaType::Shared);
+
+        Ok(VirtualAddress::new(start as usize))
+    }
+
+    /// Unmaps the shared mapping starting at `start` and drops its references to the
+    /// frames, returns how many pages it had
+    pub fn unmap_shared(&mut self, start: VirtualAddress) -> Result<usize, MapError> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| {
+                region.start == start.as_u64() && matches!(region.area_type, MemoryAreaType::Shared)
+            })
+            .ok_or(MapError::InvalidAddress)?;
+        let page_table = self.page_table.as_mut().ok_or(MapError::InvalidAddress)?;
+        let region = self.regions.remove(index);
+        let unmapped = page_table.unmap_range(start, (region.end - region.start) as usize);
+        let mut pmm = PMM.lock();
+
+        for frame in unmapped.iter() {
+            pmm.free(*frame);
+        }
+
+        Ok(unmapped.len())
+    }
+
     pub fn add_region(
         &mut self,
         start: u64,
@@ -55,6 +341,81 @@ impl MemoryDescriptor {
         });
     }
 
+    /// Physical address behind `address` in this address space
+    pub fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        self.page_table.as_ref()?.get_physical_address(address)
+    }
+
+    /// Switches the current CPU to this address space, kernel tasks get the kernel's
+    pub fn activate(&self) {
+        match &self.page_table {
+            Some(page_table) => page_table.activate(),
+            None => X86VirtualMemoryManager::activate_kernel(),
+        }
+    }
+
+    /// Unmaps every region and frees its frames and the page tables, returns how many
+    /// frames were freed
+    ///
+    /// Frames another address space still maps are orphaned, they are freed once that one
+    /// lets go of them too.
+    pub fn release(&mut self) -> usize {
+        let Some(mut page_table) = self.page_table.take() else {
+            return 0;
+        };
+        let mut frames = 0;
+        let mut orphaned = 0;
+
+        for region in self.regions.drain(..) {
+            let start = align_down(region.start as usize, PAGE_SIZE);
+            let end = align_up(region.end as usize, PAGE_SIZE);
+            let unmapped = page_table.unmap_range(VirtualAddress::new(start), end - start);
+            let mut pmm = PMM.lock();
+
+            for frame in unmapped.iter() {
+                // still mapped by another address space after this reference goes
+                if pmm.ref_count(*frame) > 1 && pmm.owner(*frame) == Some(self.owner) {
+                    pmm.set_owner(*frame, FrameOwner::Orphaned);
+                    orphaned += 1;
+                }
+
+                pmm.free(*frame);
+            }
+
+            frames += unmapped.len();
+        }
+
+        if orphaned > 0 {
+            debug!(
+                "{} frames of {} are still mapped elsewhere",
+                orphaned, self.owner
+            );
+        }
+
+        frames + page_table.release()
+    }
+
+    /// Whether the `len` bytes from `start` all lie in the task's regions, and are writable
+    /// if `write` is set, so the kernel can access them on the task's behalf
+    pub fn contains_range(&self, start: VirtualAddress, len: usize, write: bool) -> bool {
+        let Some(end) = start.as_u64().checked_add(len as u64) else {
+            return false;
+        };
+        let mut addr = start.as_u64();
+
+        // regions can sit back to back, so the range may span several
+        while addr < end {
+            match self.find_region(VirtualAddress::new(addr as usize)) {
+                Some(region) if !write || region.flags.contains(PageFlags::WRITABLE) => {
+                    addr = region.end;
+                }
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
     pub fn find_region(&self, address: VirtualAddress) -> Option<&VirtualMemoryArea> {
         let addr = address.as_u64();
 
@@ -1,7 +1,14 @@
 use spinning_top::Spinlock;
 
+pub mod cgroup;
 pub mod fd;
+pub mod group;
 pub mod memory;
 pub mod pid;
+pub mod queue;
+pub mod reaper;
 pub mod scheduler;
+pub mod slot;
 pub mod task;
+pub mod trace;
+pub mod watchdog;
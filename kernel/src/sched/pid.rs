@@ -1,21 +1,39 @@
-use core::{
-    fmt::Display,
-    sync::atomic::{AtomicUsize, Ordering},
-    usize,
-};
+use alloc::collections::vec_deque::VecDeque;
+use bitmap_allocator::{BitAlloc, BitAlloc4K};
+use core::{fmt::Display, usize};
+use lazy_static::lazy_static;
+use spinning_top::Spinlock;
+
+use crate::arch::x86::timer::{ms_to_ticks, now};
+
+/// Highest PID handed out, it also caps how many tasks can exist at once
+pub const PID_MAX: usize = 1024;
+/// How long a freed PID stays out of circulation, so stale references to it (a process
+/// group, a daemon registration) don't end up pointing at an unrelated task
+const PID_REUSE_DELAY_MS: u64 = 1000;
+
+const _: () = assert!(PID_MAX < BitAlloc4K::CAP);
+
+lazy_static! {
+    static ref PID_ALLOCATOR: Spinlock<PidAllocator> = Spinlock::new(PidAllocator::new());
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Pid(usize);
 
-static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
-
 impl Pid {
     pub const fn new(pid: usize) -> Self {
         Self(pid)
     }
 
-    pub fn next_pid() -> usize {
-        NEXT_PID.fetch_add(1, Ordering::SeqCst)
+    /// Takes the lowest free PID, `None` once all `PID_MAX` are in use or cooling down
+    pub fn alloc() -> Option<Self> {
+        PID_ALLOCATOR.lock().alloc().map(Self)
+    }
+
+    /// Returns the PID to the allocator, it is reused after `PID_REUSE_DELAY_MS`
+    pub fn free(self) {
+        PID_ALLOCATOR.lock().free(self.0);
     }
 
     pub const fn is_root(&self) -> bool {
@@ -38,3 +56,46 @@ impl Display for Pid {
         write!(f, "{}", self.0)
     }
 }
+
+struct PidAllocator {
+    free: BitAlloc4K,
+    /// Freed PIDs and the tick they were freed at, oldest first
+    cooling: VecDeque<(usize, u64)>,
+}
+
+impl PidAllocator {
+    fn new() -> Self {
+        let mut free = BitAlloc4K::DEFAULT;
+        // PID 0 is never handed out, it means "no task" in several places
+        free.insert(1..PID_MAX + 1);
+
+        Self {
+            free,
+            cooling: VecDeque::new(),
+        }
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        let now = now();
+        let delay = ms_to_ticks(PID_REUSE_DELAY_MS);
+
+        while let Some(&(pid, freed_at)) = self.cooling.front() {
+            if now < freed_at + delay {
+                break;
+            }
+
+            self.cooling.pop_front();
+            self.free.insert(pid..pid + 1);
+        }
+
+        self.free.alloc()
+    }
+
+    fn free(&mut self, pid: usize) {
+        if pid == 0 || pid > PID_MAX {
+            return;
+        }
+
+        self.cooling.push_back((pid, now()));
+    }
+}
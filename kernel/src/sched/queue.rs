@@ -0,0 +1,65 @@
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+
+use super::pid::Pid;
+
+/// Number of feedback levels, level 0 is the most urgent
+pub const LEVELS: usize = 4;
+
+/// Per-CPU multi-level feedback run queue
+///
+/// Tasks are always taken from the most urgent non-empty level, each level is FIFO.
+#[derive(Debug)]
+pub struct RunQueue {
+    levels: [VecDeque<Pid>; LEVELS],
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            levels: [const { VecDeque::new() }; LEVELS],
+        }
+    }
+
+    pub fn push_back(&mut self, pid: Pid, level: usize) {
+        self.levels[level.min(LEVELS - 1)].push_back(pid);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Pid> {
+        self.levels.iter_mut().find_map(|queue| queue.pop_front())
+    }
+
+    /// Takes the least urgent task, used when handing work to another CPU
+    pub fn pop_back(&mut self) -> Option<Pid> {
+        self.levels
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_back())
+    }
+
+    /// Most urgent level that has a task waiting
+    pub fn highest_level(&self) -> Option<usize> {
+        self.levels.iter().position(|queue| !queue.is_empty())
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&Pid) -> bool) {
+        for queue in self.levels.iter_mut() {
+            queue.retain(&mut f);
+        }
+    }
+
+    /// Empties every level, most urgent first
+    pub fn take_all(&mut self) -> Vec<Pid> {
+        self.levels
+            .iter_mut()
+            .flat_map(|queue| queue.drain(..))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.iter().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.iter().all(|queue| queue.is_empty())
+    }
+}
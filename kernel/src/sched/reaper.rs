@@ -0,0 +1,169 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use log::{info, warn};
+use spinning_top::Spinlock;
+
+use crate::{
+    arch::x86::{
+        cpu::{current_pcr, online_cpus, MAX_CPUS, PCRS},
+        interrupts::send_reschedule,
+    },
+    scheme::{schemes, CallerContext},
+};
+
+use super::{
+    cgroup,
+    pid::Pid,
+    scheduler::{get_task, get_task_mut, TASKS},
+    task::Task,
+};
+
+/// Stopped tasks that still own their memory, waiting for `reap`
+static ZOMBIES: Spinlock<Vec<Pid>> = Spinlock::new(Vec::new());
+/// Tasks already out of `TASKS`, waiting for the references other CPUs got before that
+static RETIRED: Spinlock<Vec<Retired>> = Spinlock::new(Vec::new());
+static REAPED: AtomicU64 = AtomicU64::new(0);
+static FREED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+struct Retired {
+    task: Box<Task>,
+    /// Every CPU's `quiescent` count when the task was taken out of `TASKS`
+    seen: [u64; MAX_CPUS],
+}
+
+/// Closes every file the task still has open through its scheme
+pub fn close_files(pid: Pid) {
+    let Some(task) = get_task(pid) else {
+        return;
+    };
+
+    for fd in task.fds.clone() {
+        let scheme = {
+            let schemes = schemes();
+            schemes.get(fd.scheme).map(|s| Arc::new(s))
+        };
+
+        info!("Closing fd {:?} for PID {}", fd.id, pid);
+        if let Some(scheme) = scheme {
+            if let Err(e) = scheme.close(fd.id, CallerContext::new(pid, fd.scheme)) {
+                warn!("Failed to close fd {:?} for PID {}: {}", fd.id, pid, e);
+            }
+        }
+    }
+
+    if let Some(task) = get_task_mut(pid) {
+        task.fds.clear();
+    }
+}
+
+/// Queues a stopped task to have its memory freed and its entry dropped
+pub fn bury(pid: Pid) {
+    let mut zombies = ZOMBIES.lock();
+
+    if !zombies.contains(&pid) {
+        zombies.push(pid);
+    }
+}
+
+/// Frees every zombie that no CPU is still running on or holding on to
+///
+/// A task that exits, or is killed while running on another CPU, keeps using its kernel
+/// stack until that CPU switches away, so it is left for a later call. Once it is out of
+/// `TASKS` nobody can look it up, but the memory stays until every CPU went through the
+/// scheduler, dropping whatever reference to it a syscall or interrupt had taken.
+pub fn reap() {
+    let ready: Vec<Pid> = {
+        let mut zombies = ZOMBIES.lock();
+        let (ready, busy) = zombies.drain(..).partition(|&pid| !on_any_cpu(pid));
+        *zombies = busy;
+
+        ready
+    };
+
+    for pid in ready {
+        let Some(task) = TASKS.write().remove(&pid) else {
+            continue;
+        };
+        RETIRED.lock().push(Retired {
+            task,
+            seen: quiescent_counts(),
+        });
+    }
+
+    let done: Vec<Retired> = {
+        let mut retired = RETIRED.lock();
+        let (done, waiting) = retired
+            .drain(..)
+            .partition(|retired| grace_period_over(&retired.seen));
+        *retired = waiting;
+
+        done
+    };
+
+    for Retired { mut task, .. } in done {
+        let pid = task.pid;
+        let frames = task.release();
+        let reason = task.exit_reason;
+        // the slot goes back with the task, the PID only after a delay
+        drop(task);
+        // parked by a CPU that saw it running just before it stopped
+        cgroup::detach(pid);
+        pid.free();
+
+        match reason {
+            Some(reason) => info!("Reaped task {} ({}), freed {} frames", pid, reason, frames),
+            None => info!("Reaped task {} (killed), freed {} frames", pid, frames),
+        }
+        REAPED.fetch_add(1, Ordering::Relaxed);
+        FREED_FRAMES.fetch_add(frames as u64, Ordering::Relaxed);
+    }
+}
+
+/// Zombies still waiting to be reaped
+pub fn zombies() -> usize {
+    ZOMBIES.lock().len() + RETIRED.lock().len()
+}
+
+/// Tasks reaped since boot
+pub fn reaped() -> u64 {
+    REAPED.load(Ordering::Relaxed)
+}
+
+/// Frames given back to the PMM by reaping
+pub fn freed_frames() -> u64 {
+    FREED_FRAMES.load(Ordering::Relaxed)
+}
+
+fn quiescent_counts() -> [u64; MAX_CPUS] {
+    let pcrs = unsafe { &PCRS };
+
+    core::array::from_fn(|cpu| pcrs[cpu].sched.quiescent.load(Ordering::Acquire))
+}
+
+/// Whether every CPU went through the scheduler since the counts in `seen`
+fn grace_period_over(seen: &[u64; MAX_CPUS]) -> bool {
+    let this_cpu = current_pcr().id;
+    let mut over = true;
+
+    for pcr in online_cpus() {
+        if pcr.sched.quiescent.load(Ordering::Acquire) != seen[pcr.id as usize] {
+            continue;
+        }
+
+        over = false;
+
+        // an idle CPU has no tick to get there on its own
+        if pcr.id != this_cpu && pcr.running_idle() {
+            send_reschedule(pcr.lapic_id);
+        }
+    }
+
+    over
+}
+
+fn on_any_cpu(pid: Pid) -> bool {
+    let pcrs = unsafe { &PCRS };
+
+    pcrs.iter().any(|pcr| pcr.sched.on_stack == pid.as_usize())
+}
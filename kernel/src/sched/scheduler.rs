@@ -1,93 +1,588 @@
-use alloc::{collections::btree_map::BTreeMap, vec::Vec};
-use spinning_top::{RwSpinlock, Spinlock};
-
-use crate::arch::{
-    switch_to,
-    x86::{
-        cpu::{current_pcr, current_pcr_mut, get_pcr_mut, PCRS},
-        structures::Registers,
+use core::sync::atomic::Ordering;
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, vec::Vec};
+use libjon::errno::{EBUSY, EINVAL, ESRCH};
+use spinning_top::RwSpinlock;
+
+use crate::{
+    arch::{
+        switch_to,
+        x86::{
+            cpu::{
+                current_pcr, current_pcr_mut, get_pcr, online_cpus, ProcessorControlRegion, PCRS,
+            },
+            interrupts::send_reschedule,
+            structures::Registers,
+            timer::{arm_timer, ms_to_ticks, now, ticks_to_ms},
+        },
     },
+    timer::TimerAction,
 };
 
 use super::{
+    cgroup::{self, park_if_throttled},
     pid::Pid,
-    task::{Priority, State, Task},
+    queue::{RunQueue, LEVELS},
+    reaper::{bury, close_files, reap},
+    task::{DeadlineParams, ExitReason, SchedClass, State, Task},
+    trace::{self, TraceKind},
+    watchdog,
 };
 
-pub static TASKS: RwSpinlock<BTreeMap<Pid, Task>> = RwSpinlock::new(BTreeMap::new());
-static NEXT_CPU_ID: Spinlock<u64> = Spinlock::new(0);
+/// Boxed so a task stays where it is while others are added and removed, the references
+/// handed out below point into the box
+pub static TASKS: RwSpinlock<BTreeMap<Pid, Box<Task>>> = RwSpinlock::new(BTreeMap::new());
 
-const QUANTUM_BASE: u64 = 8;
-const HIGH_PRIORITY_BONUS: u64 = 24;
-const LOW_PRIORITY_PENALTY: u64 = 6;
+/// Time a task may run at each feedback level before it is demoted, in milliseconds
+const QUANTA_MS: [u64; LEVELS] = [10, 20, 40, 80];
+/// How often, in milliseconds, every task is boosted back to its base level
+const AGING_INTERVAL_MS: u64 = 1000;
+/// Share of each CPU, in parts per thousand, that deadline tasks may reserve
+const DEADLINE_UTILIZATION_LIMIT: u64 = 900;
+/// How often, in milliseconds, each CPU tries to pull work from the busiest one
+const BALANCE_INTERVAL_MS: u64 = 250;
 
 pub unsafe fn schedule(stack_frame: &Registers) {
     let pcr = current_pcr_mut();
+    pcr.sched.quiescent.fetch_add(1, Ordering::Release);
+    let now = now();
+    pcr.sched.pit_ticks += 1;
+    pcr.sched.last_scheduled = now;
+    pcr.sched.stalled.store(false, Ordering::Release);
+    account(pcr, stack_frame.iret.cs & 0x3 == 0x3);
+    reap();
+    watchdog::check_task(pcr, stack_frame, now);
+
+    let mut slice_expired = false;
+    // the queue is unlocked first, waking a task takes other CPUs' locks
+    let actions = pcr.sched.timers.lock().expire(now);
+
+    for action in actions {
+        match action {
+            TimerAction::Wake(pid) => wake(pid),
+            TimerAction::Preempt => {
+                pcr.sched.slice_timer = None;
+                slice_expired = true;
+            }
+            TimerAction::Unthrottle(id) => resume(cgroup::unthrottle(id, now)),
+        }
+    }
+
+    // an idle CPU does no housekeeping, so it can sleep until something is due
+    if !pcr.running_idle() {
+        if now >= pcr.sched.next_balance {
+            balance(pcr);
+            kick_idle_cpus(pcr);
+            pcr.sched.next_balance = now + ms_to_ticks(BALANCE_INTERVAL_MS);
+        }
+
+        if now >= pcr.sched.next_aging {
+            age(pcr);
+            pcr.sched.next_aging = now + ms_to_ticks(AGING_INTERVAL_MS);
+        }
+    }
+
+    if now >= pcr.sched.next_watchdog {
+        watchdog::check_cpus(pcr, now);
+        pcr.sched.next_watchdog = now + ms_to_ticks(watchdog::CHECK_INTERVAL_MS);
+    }
 
-    if pcr.sched.current_pid.is_none() && pcr.sched.run_queue.is_empty() {
+    if pcr.running_idle() && pcr.sched.run_queue.lock().is_empty() {
+        steal(pcr);
+    }
+
+    let realtime = earliest_deadline(pcr);
+
+    if pcr.sched.current_pid.is_none()
+        && realtime.is_none()
+        && pcr.sched.run_queue.lock().is_empty()
+    {
         let idle_pid = pcr.idle_task();
-        pcr.sched.current_pid = Some(idle_pid);
-        let task = get_task_mut(idle_pid).unwrap();
-        task.state = State::Running;
-        return switch_to(None, task, stack_frame);
+        return dispatch(pcr, Some(idle_pid));
     }
 
-    let next_pid = match pcr.sched.current_pid {
-        Some(pid) => {
+    let next_pid = match (realtime, pcr.sched.current_pid) {
+        // deadline tasks run ahead of everything else
+        (Some(next), current) => preempt_for(pcr, current, next),
+        (None, Some(pid)) => {
             let current_task = get_task_mut(pid).unwrap();
-            current_task.quantum += 1;
-            let quantum_limit = match current_task.priority {
-                Priority::High => QUANTUM_BASE + HIGH_PRIORITY_BONUS,
-                Priority::Normal => QUANTUM_BASE,
-                Priority::Low => QUANTUM_BASE - LOW_PRIORITY_PENALTY,
+            let idle_pid = pcr.idle_task();
+            let mut run_queue = pcr.sched.run_queue.lock();
+            // a deadline task only gets here once its runtime is used up
+            let deadline = matches!(current_task.class, SchedClass::Deadline(_));
+            // out of quota, it sits out the rest of its control group's period, one that
+            // blocked or stopped isn't runnable and has nothing to sit out
+            let throttled =
+                current_task.state == State::Running && park_if_throttled(current_task, now);
+            let expired = deadline || throttled || slice_expired;
+            // the idle task gives way as soon as anything else can run
+            let preempted = if pid == idle_pid {
+                !run_queue.is_empty()
+            } else {
+                run_queue
+                    .highest_level()
+                    .is_some_and(|level| level < current_task.level)
             };
-            if current_task.quantum >= quantum_limit {
-                current_task.quantum = 0;
-                // Only add to run queue if it's not the idle task and it's still running
-                if matches!(current_task.state, State::Running) && pid != pcr.idle_task() {
-                    pcr.sched.run_queue.push_back(pid);
+
+            if expired || preempted {
+                // Only requeue it if it's not the idle task and it's still running
+                if matches!(current_task.state, State::Running)
+                    && pid != idle_pid
+                    && !deadline
+                    && !throttled
+                {
+                    if expired {
+                        // used its whole slice, so treat it as CPU bound
+                        current_task.level = (current_task.level + 1).min(LEVELS - 1);
+                    }
+                    run_queue.push_back(pid, current_task.level);
+                }
+
+                match pop_runnable(&mut run_queue) {
+                    // a throttled task waits for its next period in the idle task
+                    None if deadline || throttled => Some(idle_pid),
+                    next => next,
                 }
-                pcr.sched.run_queue.pop_front()
             } else {
                 None
             }
         }
-        None => pcr.sched.run_queue.pop_front(),
+        (None, None) => pop_runnable(&mut pcr.sched.run_queue.lock()),
     };
-    match (next_pid, pcr.sched.current_pid) {
-        (Some(next), Some(current)) => {
-            {
-                let prev_task = get_task_mut(current).unwrap();
-                prev_task.state = State::Waiting;
-                prev_task.quantum = 0; // Reset quantum when switching away
-                let next_task = get_task_mut(next).unwrap();
-                next_task.state = State::Running;
-            }
-            pcr.sched.current_pid = Some(next);
-            let prev_task_ptr = get_task_mut(current).unwrap() as *mut Task;
-            let next_task_ptr = get_task_mut(next).unwrap() as *mut Task;
-            let prev_task_ref = &mut *prev_task_ptr;
-            let next_task_ref = &*next_task_ptr;
-            switch_to(Some(prev_task_ref), next_task_ref, stack_frame);
-        }
-        (Some(next), None) => {
-            {
-                let mut tasks_guard = match TASKS.try_write() {
-                    Some(guard) => guard,
-                    None => {
-                        return;
-                    }
-                };
-                let next_task = tasks_guard.get_mut(&next).unwrap();
-                next_task.state = State::Running;
-            }
-            pcr.sched.current_pid = Some(next);
-            let next_task_ptr = get_task_mut(next).unwrap() as *mut Task;
-            let next_task_ref = &*next_task_ptr;
-            switch_to(None, next_task_ref, stack_frame);
+
+    dispatch(pcr, next_pid);
+}
+
+/// Charges the time since the last call to the running task
+fn account(pcr: &mut ProcessorControlRegion, user: bool) {
+    let now = now();
+    let elapsed = now.saturating_sub(pcr.sched.accounted_at);
+    pcr.sched.accounted_at = now;
+
+    let Some(task) = pcr.sched.current_pid.and_then(get_task_mut) else {
+        return;
+    };
+
+    if user {
+        task.stats.user_ticks += elapsed;
+    } else {
+        task.stats.system_ticks += elapsed;
+    }
+
+    if let SchedClass::Deadline(params) = &mut task.class {
+        params.remaining = params.remaining.saturating_sub(elapsed);
+    }
+
+    cgroup::charge(task, elapsed, now);
+}
+
+/// Takes the most urgent task off the queue, parking the ones whose control group is out
+/// of quota on the way
+fn pop_runnable(run_queue: &mut RunQueue) -> Option<Pid> {
+    let now = now();
+
+    while let Some(pid) = run_queue.pop_front() {
+        match get_task(pid) {
+            Some(task) if park_if_throttled(task, now) => continue,
+            _ => return Some(pid),
+        }
+    }
+
+    None
+}
+
+/// Puts tasks parked by their control group back on their CPU's run queue
+pub fn resume(pids: Vec<Pid>) {
+    for pid in pids {
+        let Some(task) = get_task(pid) else {
+            continue;
+        };
+
+        if task.state != State::Waiting {
+            continue;
+        }
+
+        let pcr = get_pcr(task.cpu);
+        pcr.sched.run_queue.lock().push_back(pid, task.level);
+
+        if pcr.id != current_pcr().id && pcr.running_idle() {
+            send_reschedule(pcr.lapic_id);
+        }
+    }
+}
+
+/// Arms the running task's time slice and programs the next timer interrupt
+///
+/// An idle CPU with no timers and no deadline task waiting for its period gets no timer
+/// interrupts at all, except the BSP's watchdog checks.
+fn rearm(pcr: &mut ProcessorControlRegion) {
+    let now = now();
+    let idle = pcr.running_idle();
+
+    if !idle && pcr.sched.slice_timer.is_none() {
+        if let Some(task) = pcr.sched.current_pid.and_then(get_task) {
+            let slice = match task.class {
+                SchedClass::Deadline(params) => params.remaining.max(1),
+                // never past what the control group has left
+                SchedClass::Normal => match cgroup::remaining(task, now) {
+                    Some(quota) => ms_to_ticks(QUANTA_MS[task.level]).min(quota.max(1)),
+                    None => ms_to_ticks(QUANTA_MS[task.level]),
+                },
+            };
+            let id = pcr
+                .sched
+                .timers
+                .lock()
+                .add(pcr.id, now + slice, TimerAction::Preempt);
+            pcr.sched.slice_timer = Some(id);
+        }
+    }
+
+    let mut next = pcr.sched.timers.lock().next_expiry();
+
+    // deadline tasks have to be picked up again when their next period starts
+    for &pid in pcr.sched.realtime.lock().iter() {
+        if let Some(SchedClass::Deadline(params)) = get_task(pid).map(|task| task.class) {
+            next = Some(next.map_or(params.next_release, |n| n.min(params.next_release)));
+        }
+    }
+
+    if !idle {
+        let housekeeping = pcr
+            .sched
+            .next_balance
+            .min(pcr.sched.next_aging)
+            .min(pcr.sched.next_watchdog);
+        next = Some(next.map_or(housekeeping, |n| n.min(housekeeping)));
+    } else if pcr.id == 0 {
+        // the BSP keeps watching the other CPUs while idle, nobody else might be awake
+        let watchdog = pcr.sched.next_watchdog;
+        next = Some(next.map_or(watchdog, |n| n.min(watchdog)));
+    }
+
+    arm_timer(next);
+}
+
+/// Sends a reschedule IPI to idle CPUs while this one has work queued, so they steal it
+/// instead of sleeping through it
+fn kick_idle_cpus(pcr: &ProcessorControlRegion) {
+    let mut queued = pcr.sched.run_queue.lock().len();
+
+    for other in online_cpus().filter(|other| other.id != pcr.id) {
+        if queued == 0 {
+            break;
+        }
+
+        if other.running_idle() && other.sched.run_queue.lock().is_empty() {
+            send_reschedule(other.lapic_id);
+            queued -= 1;
+        }
+    }
+}
+
+/// Starts new periods for this CPU's deadline tasks and returns the runnable one with the
+/// earliest absolute deadline
+fn earliest_deadline(pcr: &ProcessorControlRegion) -> Option<Pid> {
+    let now = now();
+    let mut earliest: Option<(u64, Pid)> = None;
+
+    for &pid in pcr.sched.realtime.lock().iter() {
+        let Some(task) = get_task_mut(pid) else {
+            continue;
+        };
+        let SchedClass::Deadline(params) = &mut task.class else {
+            continue;
+        };
+        params.replenish(now);
+
+        if params.remaining == 0 || matches!(task.state, State::Blocked | State::Stopped) {
+            continue;
+        }
+
+        if earliest.map_or(true, |(deadline, _)| params.absolute_deadline < deadline) {
+            earliest = Some((params.absolute_deadline, pid));
+        }
+    }
+
+    earliest.map(|(_, pid)| pid)
+}
+
+/// Switches to a deadline task, a preempted normal task goes back to the run queue
+fn preempt_for(pcr: &mut ProcessorControlRegion, current: Option<Pid>, next: Pid) -> Option<Pid> {
+    let Some(pid) = current else {
+        return Some(next);
+    };
+
+    if pid == next {
+        return None;
+    }
+
+    let idle_pid = pcr.idle_task();
+    let task = get_task_mut(pid).unwrap();
+
+    if pid != idle_pid && task.class == SchedClass::Normal && matches!(task.state, State::Running) {
+        pcr.sched.run_queue.lock().push_back(pid, task.level);
+    }
+
+    Some(next)
+}
+
+/// Moves the current task into the deadline class, or back to the normal one when `period` is 0
+///
+/// Times are in milliseconds and get rounded up to whole ticks.
+///
+/// The CPU's deadline tasks are admitted only while their combined density stays under
+/// `DEADLINE_UTILIZATION_LIMIT`, otherwise their deadlines can't be guaranteed.
+pub fn set_deadline(period: u64, runtime: u64, deadline: u64) -> Result<(), i32> {
+    let pcr = current_pcr();
+    let pid = pcr.sched.current_pid.ok_or(ESRCH)?;
+    let task = get_task_mut(pid).ok_or(ESRCH)?;
+    let mut realtime = pcr.sched.realtime.lock();
+
+    if period == 0 {
+        realtime.retain(|&p| p != pid);
+        task.class = SchedClass::Normal;
+
+        return Ok(());
+    }
+
+    if runtime == 0 || runtime > deadline || deadline > period {
+        return Err(EINVAL);
+    }
+
+    let params = DeadlineParams::new(
+        ms_to_ticks(period),
+        ms_to_ticks(runtime),
+        ms_to_ticks(deadline),
+        now(),
+    );
+    let reserved: u64 = realtime
+        .iter()
+        .filter(|&&p| p != pid)
+        .filter_map(|&p| match get_task(p)?.class {
+            SchedClass::Deadline(params) => Some(params.density()),
+            SchedClass::Normal => None,
+        })
+        .sum();
+
+    if reserved + params.density() > DEADLINE_UTILIZATION_LIMIT {
+        return Err(EBUSY);
+    }
+
+    task.class = SchedClass::Deadline(params);
+
+    if !realtime.contains(&pid) {
+        realtime.push(pid);
+    }
+
+    Ok(())
+}
+
+/// Runs newly queued work right away if this CPU is idling or a more urgent task was
+/// woken, called from the reschedule IPI
+pub unsafe fn reschedule() {
+    let pcr = current_pcr_mut();
+    pcr.sched.quiescent.fetch_add(1, Ordering::Release);
+
+    if !pcr.running_idle() {
+        let Some(task) = pcr.sched.current_pid.and_then(get_task_mut) else {
+            return;
+        };
+        let mut run_queue = pcr.sched.run_queue.lock();
+
+        if task.class != SchedClass::Normal
+            || !run_queue
+                .highest_level()
+                .is_some_and(|level| level < task.level)
+        {
+            return;
         }
-        _ => {}
+
+        run_queue.push_back(task.pid, task.level);
+        let next_pid = pop_runnable(&mut run_queue);
+        drop(run_queue);
+
+        return dispatch(pcr, next_pid);
+    }
+
+    // woken by a busy CPU that has more work than it can run
+    if pcr.sched.run_queue.lock().is_empty() {
+        steal(pcr);
+    }
+
+    let next_pid = match pcr.sched.current_pid {
+        // the task this CPU was running got killed from another CPU
+        None => Some(pick_next(pcr)),
+        Some(_) => pop_runnable(&mut pcr.sched.run_queue.lock()),
+    };
+    dispatch(pcr, next_pid);
+}
+
+/// Marks the current task as about to block, call it before arranging for the wake up
+/// so a `wake` that comes before `block_current` isn't lost
+pub fn prepare_to_block() {
+    if let Some(task) = current_task_mut() {
+        let _guard = task.wake_lock.lock();
+        task.blocking = true;
+    }
+}
+
+/// Puts the current task to sleep until `wake` is called on it
+///
+/// Meant for syscalls that have to wait, the call returns once the task is woken and
+/// scheduled again. It must run with interrupts disabled and without holding any lock.
+pub fn block_current() {
+    let pcr = current_pcr_mut();
+    let Some(pid) = pcr.sched.current_pid else {
+        return;
+    };
+    let task = get_task_mut(pid).unwrap();
+    promote(task);
+    let guard = task.wake_lock.lock();
+    task.blocking = false;
+
+    // woken up before we got the chance to sleep
+    if core::mem::take(&mut task.wake_pending) {
+        return;
     }
+
+    // from here on a `wake` finds the task blocked and queues it again
+    task.state = State::Blocked;
+    drop(guard);
+    trace::record(TraceKind::Block, pid, 0);
+    let next = pick_next(pcr);
+
+    unsafe { dispatch(pcr, Some(next)) };
+}
+
+/// Makes a blocked task runnable again on the CPU that owns it
+pub fn wake(pid: Pid) {
+    let Some(task) = get_task_mut(pid) else {
+        return;
+    };
+    let guard = task.wake_lock.lock();
+
+    match task.state {
+        State::Blocked => {}
+        // still on its way to `block_current`, maybe preempted on the way, a task that
+        // isn't blocking has nothing to be woken from
+        State::Running | State::Waiting => {
+            task.wake_pending |= task.blocking;
+            return;
+        }
+        State::Stopped => return,
+    }
+
+    task.state = State::Waiting;
+    // queued here, its group mustn't queue it again when it unthrottles
+    cgroup::detach(pid);
+    trace::record(TraceKind::Wakeup, pid, task.cpu);
+    let pcr = get_pcr(task.cpu);
+
+    // deadline tasks are picked straight from the CPU's realtime list
+    if task.class == SchedClass::Normal {
+        pcr.sched.run_queue.lock().push_back(pid, task.level);
+    }
+
+    drop(guard);
+
+    // without a tick the owner only notices at the end of its slice, so tell it now
+    let urgent = pcr
+        .sched
+        .current_pid
+        .and_then(get_task)
+        .is_some_and(|current| current.class == SchedClass::Normal && task.level < current.level);
+
+    if pcr.id != current_pcr().id && (pcr.running_idle() || urgent) {
+        send_reschedule(pcr.lapic_id);
+    }
+}
+
+/// Removes the current task and switches away from it for good
+pub fn exit_current(reason: ExitReason) -> ! {
+    if let Some(task) = current_task_mut() {
+        task.exit_reason = Some(reason);
+    }
+
+    remove_current_task();
+    let pcr = current_pcr_mut();
+    let next = pick_next(pcr);
+
+    unsafe { dispatch(pcr, Some(next)) };
+    unreachable!("switched back to an exited task");
+}
+
+/// Next task to run when the current one gives up the CPU, falls back to the idle task
+fn pick_next(pcr: &mut ProcessorControlRegion) -> Pid {
+    let idle_pid = pcr.idle_task();
+
+    earliest_deadline(pcr)
+        .or_else(|| pop_runnable(&mut pcr.sched.run_queue.lock()))
+        .unwrap_or(idle_pid)
+}
+
+/// Switches to `next_pid`, or keeps the current task for `None`, and programs the timer
+/// for whichever runs
+///
+/// The timer is armed before the switch, `pcr` belongs to the CPU the task last ran on
+/// once it is switched back to.
+unsafe fn dispatch(pcr: &mut ProcessorControlRegion, next_pid: Option<Pid>) {
+    let Some(next) = next_pid else {
+        return rearm(pcr);
+    };
+    let current = pcr.sched.current_pid;
+
+    // woken and picked again before it got to switch away
+    if current == Some(next) {
+        if let Some(task) = get_task_mut(next) {
+            task.state = State::Running;
+        }
+        return rearm(pcr);
+    }
+
+    let mut prev_task = current.and_then(get_task_mut);
+
+    if let Some(prev_task) = prev_task.as_deref_mut() {
+        // a task still marked running had the CPU taken away from it
+        let preempted = prev_task.state == State::Running;
+
+        if preempted {
+            prev_task.stats.involuntary_switches += 1;
+            prev_task.state = State::Waiting;
+        } else {
+            prev_task.stats.voluntary_switches += 1;
+        }
+
+        trace::record(TraceKind::SwitchOut, prev_task.pid, preempted as u64);
+    }
+
+    account(pcr, false);
+
+    // the slice belongs to the task being switched away from
+    if let Some(id) = pcr.sched.slice_timer.take() {
+        pcr.sched.timers.lock().cancel(id);
+    }
+
+    let now = now();
+    pcr.sched.last_scheduled = now;
+    let next_task = get_task_mut(next).unwrap();
+    next_task.state = State::Running;
+    next_task.stats.last_cpu = pcr.id;
+    next_task.stats.yielded(now);
+    trace::record(TraceKind::SwitchIn, next, next_task.level as u64);
+    pcr.sched.current_pid = Some(next);
+    rearm(pcr);
+
+    switch_to(prev_task, next_task);
+}
+
+/// Timer ticks elapsed since the timer was calibrated
+pub fn uptime_ticks() -> u64 {
+    now()
+}
+
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(uptime_ticks())
 }
 
 pub fn current_pid() -> Option<Pid> {
@@ -97,7 +592,12 @@ pub fn current_pid() -> Option<Pid> {
 pub fn get_tasks() -> Vec<&'static Task> {
     let tasks = TASKS.read();
 
-    unsafe { tasks.values().map(|task| &*(task as *const Task)).collect() }
+    unsafe {
+        tasks
+            .values()
+            .map(|task| &*(&**task as *const Task))
+            .collect()
+    }
 }
 
 pub fn current_task() -> Option<&'static Task> {
@@ -106,7 +606,7 @@ pub fn current_task() -> Option<&'static Task> {
             let tasks = TASKS.read();
             tasks
                 .get(&pid)
-                .map(|task| unsafe { &*(task as *const Task) })
+                .map(|task| unsafe { &*(&**task as *const Task) })
         }
         None => None,
     }
@@ -118,7 +618,7 @@ pub fn current_task_mut() -> Option<&'static mut Task> {
             let mut tasks = TASKS.write();
             tasks
                 .get_mut(&pid)
-                .map(|task| unsafe { &mut *(task as *mut Task) })
+                .map(|task| unsafe { &mut *(&mut **task as *mut Task) })
         }
         None => None,
     }
@@ -127,15 +627,19 @@ pub fn current_task_mut() -> Option<&'static mut Task> {
 pub fn get_task(pid: Pid) -> Option<&'static Task> {
     let tasks = TASKS.read();
 
-    // this is safe because TASKS' inner is static
-    unsafe { tasks.get(&pid).map(|task| &*(task as *const Task)) }
+    // the box outlives the reference, the reaper only frees it once no CPU can hold one
+    unsafe { tasks.get(&pid).map(|task| &*(&**task as *const Task)) }
 }
 
 pub fn get_task_mut(pid: Pid) -> Option<&'static mut Task> {
     let mut tasks = TASKS.write();
 
-    // this is safe because TASKS' inner is static
-    unsafe { tasks.get_mut(&pid).map(|task| &mut *(task as *mut Task)) }
+    // the box outlives the reference, the reaper only frees it once no CPU can hold one
+    unsafe {
+        tasks
+            .get_mut(&pid)
+            .map(|task| &mut *(&mut **task as *mut Task))
+    }
 }
 
 pub fn remove_current_task() -> Option<Pid> {
@@ -150,35 +654,166 @@ pub fn remove_current_task() -> Option<Pid> {
     None
 }
 
+/// Stops a task and closes its files, its memory is freed later by the reaper
 pub fn remove_task(pid: Pid) -> bool {
+    close_files(pid);
     let pcrs = unsafe { &mut PCRS };
+    let this_cpu = current_pcr().id;
 
     for pcr in pcrs {
-        pcr.sched.run_queue.retain(|&p| p != pid);
+        pcr.sched.run_queue.lock().retain(|&p| p != pid);
+        pcr.sched.realtime.lock().retain(|&p| p != pid);
 
         if pcr.sched.current_pid == Some(pid) {
             pcr.sched.current_pid = None;
+
+            // otherwise it keeps running the dead task until its slice ends
+            if pcr.id != this_cpu {
+                send_reschedule(pcr.lapic_id);
+            }
         }
     }
 
     let mut tasks = TASKS.write();
     if let Some(task) = tasks.get_mut(&pid) {
         task.state = State::Stopped;
+        drop(tasks);
+        // only once it is stopped, the scheduler doesn't park it again after that
+        cgroup::detach(pid);
+        bury(pid);
+
         return true;
     }
 
     false
 }
 
-pub fn add_task(task: Task) {
-    let cpu_id = {
-        let mut cpu_id = NEXT_CPU_ID.lock();
-        let id = *cpu_id;
-        *cpu_id = (*cpu_id + 1) % unsafe { PCRS.len() as u64 };
-        id
-    };
-    let pcr = get_pcr_mut(cpu_id);
+/// Places the task on the least loaded online CPU
+pub fn add_task(mut task: Task) {
+    let pcr = online_cpus()
+        .min_by_key(|pcr| load(pcr))
+        .unwrap_or_else(current_pcr);
     let pid = task.pid;
-    TASKS.write().insert(pid, task);
-    pcr.sched.run_queue.push_back(pid);
+    task.cpu = pcr.id;
+    let level = task.level;
+    TASKS.write().insert(pid, Box::new(task));
+    pcr.sched.run_queue.lock().push_back(pid, level);
+
+    if pcr.id != current_pcr().id && pcr.running_idle() {
+        send_reschedule(pcr.lapic_id);
+    }
+}
+
+/// Number of tasks a CPU is responsible for, counting the one it is running
+fn load(pcr: &ProcessorControlRegion) -> usize {
+    let queued = pcr.sched.run_queue.lock().len();
+
+    if pcr.running_idle() {
+        queued
+    } else {
+        queued + 1
+    }
+}
+
+fn busiest_cpu(pcr: &ProcessorControlRegion) -> Option<(&'static ProcessorControlRegion, usize)> {
+    online_cpus()
+        .filter(|other| other.id != pcr.id)
+        .map(|other| (other, load(other)))
+        .max_by_key(|(_, load)| *load)
+}
+
+/// Boosts every task on this CPU back to its base level, so demoted tasks can't starve
+fn age(pcr: &ProcessorControlRegion) {
+    if !pcr.running_idle() {
+        if let Some(task) = pcr.sched.current_pid.and_then(get_task_mut) {
+            task.level = task.priority.base_level();
+        }
+    }
+
+    let mut run_queue = pcr.sched.run_queue.lock();
+
+    for pid in run_queue.take_all() {
+        if let Some(task) = get_task_mut(pid) {
+            task.level = task.priority.base_level();
+            run_queue.push_back(pid, task.level);
+        }
+    }
+}
+
+/// Blocking marks a task as interactive, so it moves up a level
+fn promote(task: &mut Task) {
+    task.level = task.level.saturating_sub(1).max(task.priority.base_level());
+}
+
+/// Pulls tasks from the busiest CPU until both are roughly even
+fn balance(pcr: &mut ProcessorControlRegion) {
+    let Some((busiest, busiest_load)) = busiest_cpu(pcr) else {
+        return;
+    };
+    let this_load = load(pcr);
+
+    if busiest_load <= this_load + 1 {
+        return;
+    }
+
+    let moved = migrate(busiest, pcr, (busiest_load - this_load) / 2);
+    pcr.sched.migrations += moved;
+}
+
+/// Steals a single task when this CPU would otherwise only run its idle task
+fn steal(pcr: &mut ProcessorControlRegion) {
+    let Some((busiest, _)) = busiest_cpu(pcr) else {
+        return;
+    };
+
+    let moved = migrate(busiest, pcr, 1);
+    pcr.sched.steals += moved;
+}
+
+/// Moves up to `count` queued tasks from the back of `from`'s run queue to `to`'s
+fn migrate(from: &ProcessorControlRegion, to: &ProcessorControlRegion, count: usize) -> u64 {
+    // always lock in CPU order, otherwise two CPUs pulling from each other deadlock
+    let (mut from_queue, mut to_queue) = if from.id < to.id {
+        let from_queue = from.sched.run_queue.lock();
+        (from_queue, to.sched.run_queue.lock())
+    } else {
+        let to_queue = to.sched.run_queue.lock();
+        (from.sched.run_queue.lock(), to_queue)
+    };
+    let mut moved = 0;
+    let mut switching = Vec::new();
+
+    while moved < count as u64 {
+        let Some(pid) = from_queue.pop_back() else {
+            break;
+        };
+
+        let Some(task) = get_task_mut(pid) else {
+            continue;
+        };
+
+        // queued by its CPU on the way out, it can't run anywhere until its context is saved
+        if on_cpu(pid) {
+            switching.push((pid, task.level));
+            continue;
+        }
+
+        task.cpu = to.id;
+        to_queue.push_back(pid, task.level);
+        trace::record(TraceKind::Migrate, pid, to.id);
+        moved += 1;
+    }
+
+    for (pid, level) in switching.into_iter().rev() {
+        from_queue.push_back(pid, level);
+    }
+
+    moved
+}
+
+/// Whether some CPU is still on `pid`'s kernel stack, either running it or switching away
+/// from it, `context_switch` saves the stack pointer before it moves `on_stack` on
+fn on_cpu(pid: Pid) -> bool {
+    online_cpus()
+        .any(|pcr| unsafe { core::ptr::read_volatile(&pcr.sched.on_stack) } == pid.as_usize())
 }
@@ -0,0 +1,56 @@
+use bitmap_allocator::{BitAlloc, BitAlloc4K};
+use lazy_static::lazy_static;
+use spinning_top::Spinlock;
+
+use crate::memory::{address::VirtualAddress, PAGE_SIZE};
+
+use super::pid::PID_MAX;
+
+const KERNEL_STACK_START: usize = 0xffff888000000000;
+pub const STACK_SIZE: usize = 0x8000; // 32 KiB
+/// Unmapped page below every kernel stack, an overflow faults on it instead of running
+/// into the next slot's stack
+const GUARD_SIZE: usize = PAGE_SIZE;
+const SLOT_SIZE: usize = GUARD_SIZE + STACK_SIZE;
+
+lazy_static! {
+    static ref SLOT_ALLOCATOR: Spinlock<BitAlloc4K> = {
+        let mut alloc = BitAlloc4K::DEFAULT;
+        alloc.insert(0..PID_MAX);
+        Spinlock::new(alloc)
+    };
+}
+
+/// Place in the kernel half for one task's kernel stack
+///
+/// User memory lives in the task's own address space, only kernel stacks have to be told
+/// apart. Slots are independent from PIDs and go back to the allocator as soon as the task
+/// is reaped, its stack is unmapped by then.
+#[derive(Debug)]
+pub struct AddressSlot(usize);
+
+impl AddressSlot {
+    pub fn alloc() -> Option<Self> {
+        SLOT_ALLOCATOR.lock().alloc().map(Self)
+    }
+
+    /// Bottom of the kernel stack, right above the slot's guard page
+    pub fn kernel_stack(&self) -> VirtualAddress {
+        VirtualAddress::new(KERNEL_STACK_START + self.0 * SLOT_SIZE + GUARD_SIZE)
+    }
+}
+
+/// Whether `address` is in the guard page of some kernel stack
+pub fn is_kernel_stack_guard(address: VirtualAddress) -> bool {
+    let Some(offset) = address.as_usize().checked_sub(KERNEL_STACK_START) else {
+        return false;
+    };
+
+    offset < PID_MAX * SLOT_SIZE && offset % SLOT_SIZE < GUARD_SIZE
+}
+
+impl Drop for AddressSlot {
+    fn drop(&mut self) {
+        SLOT_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
@@ -1,27 +1,35 @@
+use core::fmt::Display;
+
 use alloc::{string::String, vec::Vec};
-use libjon::fd::FileDescriptorId;
-use log::{debug, info};
+use libjon::{
+    errno::{EAGAIN, ENOEXEC, ENOMEM},
+    fd::FileDescriptorId,
+};
+use log::{debug, error, info, warn};
 use spinning_top::Spinlock;
 
 use crate::{
-    arch::x86::structures::Registers,
+    arch::x86::{cpu::current_pcr, idle::idle_loop, sched::Context, structures::Registers},
     memory::{
         address::VirtualAddress,
         loader::{elf::ElfLoader, Loader},
+        physical::FrameOwner,
         stack::Stack,
-        PAGE_SIZE,
     },
-    sched::pid::Pid,
+    sched::{
+        pid::Pid,
+        queue::LEVELS,
+        scheduler::uptime_ticks,
+        slot::{AddressSlot, STACK_SIZE},
+    },
+    scheme::schemes,
 };
 
-use super::{fd::FileDescriptor, memory::MemoryDescriptor};
+use super::{
+    fd::FileDescriptor,
+    memory::{MemoryDescriptor, BINARY_START, USER_STACK_START},
+};
 
-pub const BINARY_START: usize = 0x400000;
-const KERNEL_STACK_START: usize = 0xffff888000000000;
-const USER_STACK_START: usize = 0x0000700000000000;
-const STACK_SIZE: usize = 0x8000; // 32 KiB
-static IDLE_BINARY: Spinlock<Option<(MemoryDescriptor, VirtualAddress)>> = Spinlock::new(None);
-static LOADER: Spinlock<ElfLoader> = Spinlock::new(ElfLoader::new());
 pub const BINARIES: [&[u8]; 4] = [
     include_bytes!(
         "../../../drivers/reincarnation/target/x86_64-unknown-none/release/reincarnation"
@@ -35,16 +43,73 @@ pub const BINARIES: [&[u8]; 4] = [
 pub struct Task {
     pub pid: Pid,
     pub parent: Option<Pid>,
+    /// Process group, signals sent to a negative PID reach every task in it
+    pub pgid: Pid,
+    /// Session, a set of process groups started by its leader
+    pub sid: Pid,
     pub name: String,
     pub state: State,
-    pub quantum: u64,
     pub priority: Priority,
-    pub context: Registers,
+    pub context: Context,
+    /// Set when `wake` races with the task getting ready to block
+    pub wake_pending: bool,
+    /// Between `prepare_to_block` and `block_current`, only then is a wake kept pending
+    pub blocking: bool,
+    /// Held while `state`, `blocking` and `wake_pending` change hands between a task going
+    /// to sleep and whoever wakes it
+    pub wake_lock: Spinlock<()>,
     pub fds: Vec<FileDescriptor>,
     pub kernel_stack: Stack,
     pub user_stack: Stack,
     pub memory_descriptor: MemoryDescriptor,
     pub next_fd: usize,
+    /// CPU whose run queue currently owns the task
+    pub cpu: u64,
+    /// Current feedback level, 0 is the most urgent
+    pub level: usize,
+    pub class: SchedClass,
+    pub stats: TaskStats,
+    /// Control group whose CPU quota the task counts against
+    pub cgroup: Option<usize>,
+    /// Where the task's kernel stack lives
+    pub slot: AddressSlot,
+    /// Why the task ended, `None` while it runs or when it was removed by another task
+    pub exit_reason: Option<ExitReason>,
+}
+
+/// CPU accounting, kept up to date by the scheduler
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskStats {
+    /// Ticks spent in user mode
+    pub user_ticks: u64,
+    /// Ticks spent inside the kernel, e.g. in a syscall
+    pub system_ticks: u64,
+    /// Switches away because the task stopped or blocked
+    pub voluntary_switches: u64,
+    /// Switches away because the task was preempted
+    pub involuntary_switches: u64,
+    pub last_cpu: u64,
+    /// Uptime, in ticks, when the task was created
+    pub start_time: u64,
+    /// Uptime, in ticks, of the task's last syscall or switch in
+    pub running_since: u64,
+    /// Set once the watchdog warned about the current stretch
+    pub stall_reported: bool,
+}
+
+impl TaskStats {
+    pub fn new() -> Self {
+        Self {
+            start_time: uptime_ticks(),
+            ..Self::default()
+        }
+    }
+
+    /// Starts a new stretch of running for the watchdog, on every syscall and switch in
+    pub fn yielded(&mut self, now: u64) {
+        self.running_since = now;
+        self.stall_reported = false;
+    }
 }
 
 #[repr(u8)]
@@ -55,6 +120,103 @@ pub enum Priority {
     High,
 }
 
+impl Priority {
+    /// Feedback level a task starts at and is boosted back to by aging
+    pub fn base_level(&self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedClass {
+    /// Scheduled by the multi-level feedback queue
+    Normal,
+    /// Earliest deadline first, runs ahead of every normal task
+    Deadline(DeadlineParams),
+}
+
+/// Reservation of a deadline task, all times are in timer ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParams {
+    pub period: u64,
+    pub runtime: u64,
+    /// Relative to the start of each period
+    pub deadline: u64,
+    /// Runtime left in the current period
+    pub remaining: u64,
+    pub absolute_deadline: u64,
+    /// Start of the next period
+    pub next_release: u64,
+}
+
+impl DeadlineParams {
+    pub fn new(period: u64, runtime: u64, deadline: u64, now: u64) -> Self {
+        Self {
+            period,
+            runtime,
+            deadline,
+            remaining: runtime,
+            absolute_deadline: now + deadline,
+            next_release: now + period,
+        }
+    }
+
+    /// Share of a CPU the task reserves, in parts per thousand
+    pub fn density(&self) -> u64 {
+        self.runtime * 1000 / self.deadline
+    }
+
+    /// Starts a new period once the current one is over, refilling the runtime
+    pub fn replenish(&mut self, now: u64) {
+        if now < self.next_release {
+            return;
+        }
+
+        // a task that missed whole periods restarts from now instead of catching up
+        let release = if now - self.next_release < self.period {
+            self.next_release
+        } else {
+            now
+        };
+        self.remaining = self.runtime;
+        self.absolute_deadline = release + self.deadline;
+        self.next_release = release + self.period;
+    }
+}
+
+/// How a task ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Called `SYS_EXIT` with this code
+    Exited(usize),
+    /// Touched memory it has no access to
+    Segfault,
+    /// Ran into the guard page below its stack
+    StackOverflow,
+    IllegalInstruction,
+    /// Divided by zero or hit a floating point exception
+    ArithmeticError,
+    /// Misaligned access with alignment checking on
+    BusError,
+}
+
+impl Display for ExitReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ExitReason::Exited(code) => write!(f, "exited with code {}", code),
+            ExitReason::Segfault => write!(f, "segmentation fault"),
+            ExitReason::StackOverflow => write!(f, "stack overflow"),
+            ExitReason::IllegalInstruction => write!(f, "illegal instruction"),
+            ExitReason::ArithmeticError => write!(f, "arithmetic error"),
+            ExitReason::BusError => write!(f, "bus error"),
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
@@ -65,106 +227,226 @@ pub enum State {
 }
 
 impl Task {
-    pub fn new(name: &str, binary: &[u8]) -> Self {
-        let pid = Pid::new(Pid::next_pid());
-        info!("Creating task {} with PID {}", name, pid);
-        let kernel_stack = Stack::new(
-            VirtualAddress::new(KERNEL_STACK_START + (pid.as_usize() - 1) * STACK_SIZE),
-            STACK_SIZE,
-        );
-        let user_stack = Stack::new(
-            VirtualAddress::new(USER_STACK_START + (pid.as_usize() - 1) * STACK_SIZE),
+    /// Fails with EAGAIN when the PID or kernel stack slots run out, ENOMEM when its
+    /// address space can't be built and ENOEXEC when the binary doesn't load
+    pub fn new(name: &str, binary: &[u8]) -> Result<Self, i32> {
+        // the PID comes first, the frames of the address space are allocated to it
+        let (pid, slot) = Self::alloc_ids()?;
+        let mut memory_descriptor = match MemoryDescriptor::new(FrameOwner::Task(pid)) {
+            Ok(memory_descriptor) => memory_descriptor,
+            Err(_) => {
+                pid.free();
+                return Err(ENOMEM);
+            }
+        };
+        let user_stack = match Stack::user(
+            &mut memory_descriptor,
+            VirtualAddress::new(USER_STACK_START),
             STACK_SIZE,
-        );
-        let mut context = Registers::new();
-        let bin_addr = VirtualAddress::new(BINARY_START + (pid.as_usize() - 1) * PAGE_SIZE * 128); // TODO: Use a better dynamic address
+        ) {
+            Ok(stack) => stack,
+            Err(_) => {
+                memory_descriptor.release();
+                pid.free();
+                return Err(ENOMEM);
+            }
+        };
+        let bin_addr = VirtualAddress::new(BINARY_START);
         let loader = ElfLoader::new();
-        let (memory_descriptor, rip) = loader.load(bin_addr, binary).unwrap();
+        let rip = match loader.load(&mut memory_descriptor, bin_addr, binary) {
+            Ok(rip) => rip,
+            Err(e) => {
+                error!("Failed to load {}: {}", name, e);
+                memory_descriptor.release();
+                pid.free();
+                return Err(ENOEXEC);
+            }
+        };
         debug!("Loaded binary at {:#x?}", bin_addr);
 
-        context.iret.rsp = user_stack.top().as_u64();
-        context.iret.rip = rip.as_u64();
+        info!("Creating task {} with PID {}", name, pid);
+        let kernel_stack = Stack::new(slot.kernel_stack(), STACK_SIZE);
+        let mut registers = Registers::new();
 
-        Self {
+        registers.iret.rsp = user_stack.top().as_u64();
+        registers.iret.rip = rip.as_u64();
+        let context = Context::new(kernel_stack.top(), registers);
+
+        Ok(Self {
             pid,
             name: String::from(name),
             parent: None,
+            pgid: pid,
+            sid: pid,
             kernel_stack,
             user_stack,
             context,
             state: State::Waiting,
             memory_descriptor,
-            quantum: 0,
             priority: Priority::Normal,
             fds: Vec::new(),
             next_fd: 1,
+            cpu: 0,
+            level: Priority::Normal.base_level(),
+            class: SchedClass::Normal,
+            stats: TaskStats::new(),
+            wake_pending: false,
+            blocking: false,
+            wake_lock: Spinlock::new(()),
+            cgroup: None,
+            slot,
+            exit_reason: None,
+        })
+    }
+
+    /// Copy of the task for `SYS_FORK`, resuming in user mode with `registers`
+    ///
+    /// It gets its own PID and kernel stack, a copy-on-write copy of the address space and
+    /// a copy of every descriptor. Must run on the task's own address space.
+    pub fn fork(&mut self, registers: Registers) -> Result<Self, i32> {
+        let (pid, slot) = Self::alloc_ids()?;
+        let memory_descriptor = match self.memory_descriptor.fork(FrameOwner::Task(pid)) {
+            Ok(memory_descriptor) => memory_descriptor,
+            Err(_) => {
+                pid.free();
+                return Err(ENOMEM);
+            }
+        };
+        info!("Forking task {} ({}) as PID {}", self.name, self.pid, pid);
+        let kernel_stack = Stack::new(slot.kernel_stack(), STACK_SIZE);
+        let context = Context::new(kernel_stack.top(), registers);
+
+        Ok(Self {
+            pid,
+            name: self.name.clone(),
+            parent: Some(self.pid),
+            pgid: self.pgid,
+            sid: self.sid,
+            kernel_stack,
+            user_stack: self.user_stack.fork(),
+            context,
+            state: State::Waiting,
+            memory_descriptor,
+            priority: self.priority,
+            fds: self.fork_fds(),
+            next_fd: self.next_fd,
+            cpu: 0,
+            level: self.priority.base_level(),
+            class: SchedClass::Normal,
+            stats: TaskStats::new(),
+            wake_pending: false,
+            blocking: false,
+            wake_lock: Spinlock::new(()),
+            cgroup: self.cgroup,
+            slot,
+            exit_reason: None,
+        })
+    }
+
+    /// Copies of the task's descriptors under the same numbers, one a scheme can't duplicate
+    /// is left out
+    fn fork_fds(&self) -> Vec<FileDescriptor> {
+        let schemes = schemes();
+
+        self.fds
+            .iter()
+            .filter_map(|fd| {
+                let scheme = schemes.get(fd.scheme)?;
+                let mut copy = FileDescriptor::new(fd.scheme, fd.flags);
+                copy.number = fd.number;
+                copy.offset = fd.offset;
+
+                match scheme.dup(fd.id, copy.id) {
+                    Ok(()) => Some(copy),
+                    Err(e) => {
+                        warn!("Failed to copy fd {:?} of {}: {}", fd.id, self.pid, e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn alloc_ids() -> Result<(Pid, AddressSlot), i32> {
+        let pid = Pid::alloc().ok_or(EAGAIN)?;
+
+        match AddressSlot::alloc() {
+            Some(slot) => Ok((pid, slot)),
+            None => {
+                pid.free();
+                Err(EAGAIN)
+            }
         }
     }
 
-    pub fn reincarnation() -> Self {
+    pub fn reincarnation() -> Result<Self, i32> {
         Self::new("reincarnation", &BINARIES[0][..])
     }
 
-    pub fn task_manager() -> Self {
+    pub fn task_manager() -> Result<Self, i32> {
         Self::new("task_manager", &BINARIES[1][..])
     }
 
-    pub fn random() -> Self {
+    pub fn random() -> Result<Self, i32> {
         Self::new("random", &BINARIES[2][..])
     }
 
-    pub fn random_echo() -> Self {
+    pub fn random_echo() -> Result<Self, i32> {
         Self::new("random-echo", &BINARIES[3][..])
     }
 
+    /// Per-CPU idle task, it runs `idle_loop` in ring 0 on its own kernel stack
     pub fn idle() -> Self {
-        let pid = Pid::new(Pid::next_pid());
-        let mut binary = IDLE_BINARY.lock();
-
-        if binary.is_none() {
-            *binary = Some(
-                LOADER
-                    .lock()
-                    .load(
-                        VirtualAddress::new(BINARY_START + (pid.as_usize() - 1) * PAGE_SIZE * 20),
-                        include_bytes!(
-                            "../../../drivers/idle/target/x86_64-unknown-none/release/idle"
-                        ),
-                    )
-                    .unwrap(),
-            );
-        }
-        drop(binary);
-
-        let kernel_stack = Stack::new(
-            VirtualAddress::new(KERNEL_STACK_START + (pid.as_usize() - 1) * STACK_SIZE),
-            PAGE_SIZE,
-        );
-        let user_stack = Stack::new(
-            VirtualAddress::new(USER_STACK_START + (pid.as_usize() - 1) * STACK_SIZE),
-            PAGE_SIZE,
-        );
-        let mut context = Registers::new();
-        context.iret.rsp = 0;
-        context.iret.rip = IDLE_BINARY.lock().as_ref().unwrap().1.as_u64();
+        let (pid, slot) = Self::alloc_ids().expect("no PID left for the idle task");
+        let kernel_stack = Stack::new(slot.kernel_stack(), STACK_SIZE);
+        let selectors = current_pcr().selectors.as_ref().unwrap();
+        let mut registers = Registers::new();
+        registers.iret.cs = selectors.kernel_code_selector.0 as u64;
+        registers.iret.ss = selectors.kernel_data_selector.0 as u64;
+        // as if `idle_loop` had been called, so the stack is aligned the way it expects
+        registers.iret.rsp = kernel_stack.top().as_u64() - 8;
+        registers.iret.rip = idle_loop as usize as u64;
+        let context = Context::new(kernel_stack.top(), registers);
 
         Task {
             pid,
             parent: None,
+            pgid: pid,
+            sid: pid,
             name: String::from("idle"),
             state: State::Waiting,
-            quantum: 0,
             priority: Priority::Normal,
             context,
             fds: Vec::new(),
             kernel_stack,
-            user_stack,
-            memory_descriptor: IDLE_BINARY.lock().as_ref().unwrap().0.clone(),
+            user_stack: Stack::empty(),
+            memory_descriptor: MemoryDescriptor::kernel(),
             next_fd: 1,
+            cpu: current_pcr().id,
+            level: LEVELS - 1,
+            class: SchedClass::Normal,
+            stats: TaskStats::new(),
+            wake_pending: false,
+            blocking: false,
+            wake_lock: Spinlock::new(()),
+            cgroup: None,
+            slot,
+            exit_reason: None,
         }
     }
 
-    pub fn add_file(&mut self, descriptor: FileDescriptor) {
+    /// Gives the task's memory back, its address space with everything mapped in it and its
+    /// kernel stack, returns how many frames were freed
+    ///
+    /// Must only run once no CPU is executing on the task's stacks or has its page table
+    /// loaded.
+    pub fn release(&mut self) -> usize {
+        self.memory_descriptor.release() + self.kernel_stack.release()
+    }
+
+    /// Gives the descriptor the task's next fd number
+    pub fn add_file(&mut self, mut descriptor: FileDescriptor) {
+        descriptor.number = self.next_fd;
         debug!("Adding file descriptor: {:?}", descriptor);
         self.fds.push(descriptor);
         self.next_fd += 1;
@@ -0,0 +1,205 @@
+use core::{
+    cell::UnsafeCell,
+    fmt::Write,
+    sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    arch::x86::{
+        cpu::{current_pcr, MAX_CPUS},
+        timer::now_us,
+    },
+    println,
+};
+
+use super::{pid::Pid, scheduler::get_tasks};
+
+/// Events each CPU keeps, the oldest get overwritten
+const EVENTS_PER_CPU: usize = 2048;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Events older than this, in microseconds, were cleared
+static CLEARED_AT: AtomicU64 = AtomicU64::new(0);
+static BUFFERS: [TraceBuffer; MAX_CPUS] = [const { TraceBuffer::new() }; MAX_CPUS];
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    /// `arg` is the feedback level the task runs at
+    SwitchIn,
+    /// `arg` is 1 when the task was preempted, 0 when it blocked or exited
+    SwitchOut,
+    /// `arg` is the CPU the task was woken on
+    Wakeup,
+    Block,
+    /// `arg` is the CPU the task was pulled to
+    Migrate,
+}
+
+impl TraceKind {
+    fn name(self) -> &'static str {
+        match self {
+            TraceKind::SwitchIn => "switch-in",
+            TraceKind::SwitchOut => "switch-out",
+            TraceKind::Wakeup => "wakeup",
+            TraceKind::Block => "block",
+            TraceKind::Migrate => "migrate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TraceEvent {
+    time_us: u64,
+    pid: usize,
+    arg: u64,
+    kind: TraceKind,
+}
+
+impl TraceEvent {
+    const fn empty() -> Self {
+        Self {
+            time_us: 0,
+            pid: 0,
+            arg: 0,
+            kind: TraceKind::SwitchIn,
+        }
+    }
+}
+
+/// Slot guarded by a sequence number, odd while the event in it is being written
+struct Slot {
+    seq: AtomicU64,
+    event: UnsafeCell<TraceEvent>,
+}
+
+/// Ring of one CPU's events
+///
+/// Only its own CPU writes to it, always with interrupts off, so writing needs no lock.
+/// Readers on any CPU check the slot's sequence number around the copy and skip events that
+/// got overwritten meanwhile.
+struct TraceBuffer {
+    slots: [Slot; EVENTS_PER_CPU],
+    /// Events ever written
+    head: AtomicUsize,
+}
+
+unsafe impl Sync for TraceBuffer {}
+
+impl TraceBuffer {
+    const fn new() -> Self {
+        Self {
+            slots: [const {
+                Slot {
+                    seq: AtomicU64::new(0),
+                    event: UnsafeCell::new(TraceEvent::empty()),
+                }
+            }; EVENTS_PER_CPU],
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, event: TraceEvent) {
+        let n = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[n % EVENTS_PER_CPU];
+
+        slot.seq.store(2 * n as u64 + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { slot.event.get().write_volatile(event) };
+        slot.seq.store(2 * n as u64 + 2, Ordering::Release);
+        self.head.store(n + 1, Ordering::Release);
+    }
+
+    /// Copies out the events still in the ring, oldest first
+    fn snapshot(&self) -> Vec<TraceEvent> {
+        let head = self.head.load(Ordering::Acquire);
+        let mut events = Vec::with_capacity(head.min(EVENTS_PER_CPU));
+
+        for n in head.saturating_sub(EVENTS_PER_CPU)..head {
+            let slot = &self.slots[n % EVENTS_PER_CPU];
+            let expected = 2 * n as u64 + 2;
+
+            if slot.seq.load(Ordering::Acquire) != expected {
+                continue;
+            }
+
+            let event = unsafe { slot.event.get().read_volatile() };
+            fence(Ordering::Acquire);
+
+            if slot.seq.load(Ordering::Relaxed) == expected {
+                events.push(event);
+            }
+        }
+
+        events
+    }
+}
+
+/// Records an event on the current CPU's ring, must run with interrupts off
+pub fn record(kind: TraceKind, pid: Pid, arg: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let cpu = current_pcr().id as usize;
+    BUFFERS[cpu].push(TraceEvent {
+        time_us: now_us(),
+        pid: pid.as_usize(),
+        arg,
+        kind,
+    });
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Hides every event recorded so far
+pub fn clear() {
+    CLEARED_AT.store(now_us(), Ordering::Relaxed);
+}
+
+/// Task names followed by every CPU's events, one per line:
+///
+/// ```text
+/// sched-trace-task <pid> <name>
+/// sched-trace <cpu> <time us> <event> <pid> <arg>
+/// ```
+pub fn render() -> String {
+    let mut out = String::new();
+    for_each_line(|line| writeln!(out, "{}", line).unwrap());
+
+    out
+}
+
+/// Prints the same lines as `render` to the serial port, to be fed to the `sched-trace` tool
+pub fn dump() {
+    for_each_line(|line| println!("{}", line));
+}
+
+fn for_each_line(mut f: impl FnMut(core::fmt::Arguments)) {
+    for task in get_tasks() {
+        f(format_args!("sched-trace-task {} {}", task.pid, task.name));
+    }
+
+    let cleared_at = CLEARED_AT.load(Ordering::Relaxed);
+
+    for (cpu, buffer) in BUFFERS.iter().enumerate() {
+        for event in buffer.snapshot() {
+            if event.time_us < cleared_at {
+                continue;
+            }
+
+            f(format_args!(
+                "sched-trace {} {} {} {} {}",
+                cpu,
+                event.time_us,
+                event.kind.name(),
+                event.pid,
+                event.arg
+            ));
+        }
+    }
+}
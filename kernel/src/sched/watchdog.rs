@@ -0,0 +1,187 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use alloc::string::String;
+use log::warn;
+
+use crate::{
+    arch::x86::{
+        cpu::{current_pcr, online_cpus, ProcessorControlRegion},
+        interrupts::send_nmi,
+        structures::Registers,
+        timer::{ms_to_ticks, ticks_to_ms},
+    },
+    scheme::pipe,
+};
+
+use super::{pid::Pid, scheduler::get_task_mut};
+
+/// A busy CPU that hasn't been through the scheduler for this long is stuck, in milliseconds
+pub const CPU_LOCKUP_MS: u64 = 2000;
+/// A task that runs this long without a syscall or a switch is stuck, in milliseconds
+pub const TASK_LOCKUP_MS: u64 = 5000;
+/// How often, in milliseconds, the other CPUs are checked
+pub const CHECK_INTERVAL_MS: u64 = 1000;
+
+/// Pipe `reincarnation` reads its requests from
+const REINCARNATION_PIPE: &str = "1/read";
+/// `MessageType::Stalled` in the drivers' IPC
+const MESSAGE_STALLED: u32 = 4;
+
+static RESTART: AtomicBool = AtomicBool::new(false);
+static CPU_LOCKUPS: AtomicU64 = AtomicU64::new(0);
+static TASK_LOCKUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Layout of the drivers' IPC `Message`
+#[repr(C)]
+struct Message {
+    message_type: u32,
+    data: [u8; 16],
+    origin: usize,
+}
+
+impl Message {
+    fn to_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Message as *const u8,
+                core::mem::size_of::<Message>(),
+            )
+        }
+    }
+}
+
+/// Warns about the task running on this CPU if it has hogged it for too long
+///
+/// Called on every timer interrupt, `registers` are the ones the task was interrupted with.
+/// Each stretch is only reported once.
+pub fn check_task(pcr: &ProcessorControlRegion, registers: &Registers, now: u64) {
+    if pcr.running_idle() {
+        return;
+    }
+
+    let Some(task) = pcr.sched.current_pid.and_then(get_task_mut) else {
+        return;
+    };
+    let running = now.saturating_sub(task.stats.running_since);
+
+    if running < ms_to_ticks(TASK_LOCKUP_MS) || task.stats.stall_reported {
+        return;
+    }
+
+    task.stats.stall_reported = true;
+    TASK_LOCKUPS.fetch_add(1, Ordering::Relaxed);
+    warn!(
+        "Watchdog: task {} ({}) has run for {} ms on CPU {} without a syscall\n{:#?}",
+        task.pid,
+        task.name,
+        ticks_to_ms(running),
+        pcr.id,
+        registers
+    );
+
+    if RESTART.load(Ordering::Relaxed) {
+        notify_reincarnation(task.pid);
+    }
+}
+
+/// Warns about busy CPUs that stopped going through the scheduler, e.g. spinning on a lock
+/// with interrupts off
+///
+/// They can't take a timer interrupt, so they get an NMI that saves their registers, which
+/// are printed on the next check. Idle CPUs sleep without a tick and are left alone.
+pub fn check_cpus(pcr: &ProcessorControlRegion, now: u64) {
+    for other in online_cpus().filter(|other| other.id != pcr.id) {
+        if let Some(registers) = other.sched.stall_registers.lock().take() {
+            warn!(
+                "Watchdog: CPU {} stuck running task {:?}\n{:#?}",
+                other.id, other.sched.current_pid, registers
+            );
+        }
+
+        if other.running_idle() {
+            continue;
+        }
+
+        let silent = now.saturating_sub(other.sched.last_scheduled);
+
+        if silent < ms_to_ticks(CPU_LOCKUP_MS) || other.sched.stalled.swap(true, Ordering::AcqRel) {
+            continue;
+        }
+
+        CPU_LOCKUPS.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Watchdog: CPU {} hasn't scheduled for {} ms while running task {:?}",
+            other.id,
+            ticks_to_ms(silent),
+            other.sched.current_pid
+        );
+        send_nmi(other.lapic_id);
+    }
+}
+
+/// Saves the registers of this CPU for `check_cpus` if the watchdog reported it as stuck,
+/// returns false for any other NMI
+///
+/// Runs in the NMI, so it takes no lock that the interrupted code could be holding.
+pub fn report_stalled_cpu(registers: &Registers) -> bool {
+    let pcr = current_pcr();
+
+    if !pcr.sched.stalled.load(Ordering::Acquire) {
+        return false;
+    }
+
+    // only another CPU taking the last dump holds it, that one is dropped if it's busy
+    if let Some(mut slot) = pcr.sched.stall_registers.try_lock() {
+        *slot = Some(registers.clone());
+    }
+
+    true
+}
+
+/// Whether stuck tasks are handed to `reincarnation` to be restarted
+pub fn set_restart(enabled: bool) {
+    RESTART.store(enabled, Ordering::Relaxed);
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+    let restart = if RESTART.load(Ordering::Relaxed) {
+        "on"
+    } else {
+        "off"
+    };
+
+    writeln!(out, "cpu_lockup: {} ms", CPU_LOCKUP_MS).unwrap();
+    writeln!(out, "task_lockup: {} ms", TASK_LOCKUP_MS).unwrap();
+    writeln!(out, "cpu_lockups: {}", CPU_LOCKUPS.load(Ordering::Relaxed)).unwrap();
+    writeln!(
+        out,
+        "task_lockups: {}",
+        TASK_LOCKUPS.load(Ordering::Relaxed)
+    )
+    .unwrap();
+    writeln!(out, "restart: {}", restart).unwrap();
+
+    out
+}
+
+/// Asks `reincarnation` to restart the task, it gets no reply
+fn notify_reincarnation(pid: Pid) {
+    let mut data = [0u8; 16];
+    data[..8].copy_from_slice(&pid.as_usize().to_ne_bytes());
+    let message = Message {
+        message_type: MESSAGE_STALLED,
+        data,
+        origin: 0,
+    };
+
+    if let Err(e) = pipe::post(REINCARNATION_PIPE, message.to_bytes()) {
+        warn!(
+            "Watchdog: failed to notify reincarnation about {}: {}",
+            pid, e
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use alloc::{collections::btree_map::BTreeMap, string::String, vec};
+use libjon::{
+    errno::{EBADF, EINVAL, ENOENT},
+    fd::{FileDescriptorFlags, FileDescriptorId},
+};
+use log::debug;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    arch::x86::timer::now,
+    sched::{
+        cgroup::{self, attach, create, find, set_quota},
+        fd::FileDescriptor,
+        pid::Pid,
+        scheduler::{get_task_mut, resume},
+    },
+};
+
+use super::{CallerContext, KernelScheme, Whence};
+
+static HANDLES: RwSpinlock<BTreeMap<FileDescriptorId, CGroupHandle>> =
+    RwSpinlock::new(BTreeMap::new());
+
+#[derive(Debug, Clone)]
+struct CGroupHandle {
+    /// `None` for the root, which lists the groups
+    group: Option<usize>,
+    offset: usize,
+}
+
+/// Control groups capping the CPU time of their member tasks
+///
+/// `cgroup:<name>` opened with `O_CREAT` creates the group. Reading a group shows its
+/// quota, usage and members, and it is configured by writing one command at a time:
+///
+/// - `quota <ms> <period ms>` lets members run for `ms` every `period ms`, `quota max`
+///   lifts the cap
+/// - `add <pid>` moves a task into the group, `remove <pid>` takes it out
+#[derive(Debug)]
+pub struct CGroupScheme;
+
+impl CGroupScheme {
+    fn command(group: usize, command: &str) -> Result<(), i32> {
+        let mut words = command.split_whitespace();
+
+        match (words.next(), words.next(), words.next()) {
+            (Some("quota"), Some("max"), None) => {
+                let period = cgroup::period_ms(group).ok_or(EINVAL)?;
+                set_quota(group, None, period, now())
+            }
+            (Some("quota"), Some(quota), Some(period)) => {
+                let quota = quota.parse().map_err(|_| EINVAL)?;
+                let period = period.parse().map_err(|_| EINVAL)?;
+                set_quota(group, Some(quota), period, now())
+            }
+            (Some("add"), Some(pid), None) => Self::attach(Some(group), pid),
+            (Some("remove"), Some(pid), None) => Self::attach(None, pid),
+            _ => Err(EINVAL),
+        }
+    }
+
+    fn attach(group: Option<usize>, pid: &str) -> Result<(), i32> {
+        let pid = Pid::new(pid.parse().map_err(|_| EINVAL)?);
+
+        if attach(group, pid)? {
+            resume(vec![pid]);
+        }
+
+        Ok(())
+    }
+}
+
+impl KernelScheme for CGroupScheme {
+    fn open(
+        &self,
+        path: &str,
+        flags: FileDescriptorFlags,
+        ctx: CallerContext,
+    ) -> Result<FileDescriptorId, i32> {
+        debug!("Opening cgroup: {}", path);
+        let group = match path {
+            "" => None,
+            name => match find(name) {
+                Some(id) => Some(id),
+                None if flags.contains(FileDescriptorFlags::O_CREAT) => Some(create(name, now())?),
+                None => return Err(ENOENT),
+            },
+        };
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        let descriptor = FileDescriptor::new(ctx.scheme, flags);
+        let id = descriptor.id;
+        HANDLES
+            .write()
+            .insert(id, CGroupHandle { group, offset: 0 });
+        task.add_file(descriptor);
+
+        Ok(id)
+    }
+
+    fn read(
+        &self,
+        descriptor_id: FileDescriptorId,
+        buf: &mut [u8],
+        count: usize,
+    ) -> Result<usize, i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&descriptor_id).ok_or(EBADF)?;
+        let content = match handle.group {
+            Some(group) => cgroup::render(group, now()),
+            None => cgroup::render_list(),
+        };
+        let bytes = content.as_bytes();
+
+        if handle.offset >= bytes.len() {
+            return Ok(0);
+        }
+
+        let bytes_to_read = count.min(buf.len()).min(bytes.len() - handle.offset);
+        buf[..bytes_to_read].copy_from_slice(&bytes[handle.offset..handle.offset + bytes_to_read]);
+        handle.offset += bytes_to_read;
+
+        Ok(bytes_to_read)
+    }
+
+    fn write(
+        &self,
+        descriptor_id: FileDescriptorId,
+        buf: &[u8],
+        count: usize,
+    ) -> Result<usize, i32> {
+        let group = HANDLES
+            .read()
+            .get(&descriptor_id)
+            .ok_or(EBADF)?
+            .group
+            .ok_or(EINVAL)?;
+        let bytes_to_write = count.min(buf.len());
+        let command = String::from_utf8_lossy(&buf[..bytes_to_write]);
+        Self::command(group, &command)?;
+
+        Ok(bytes_to_write)
+    }
+
+    fn lseek(
+        &self,
+        descriptor_id: FileDescriptorId,
+        offset: usize,
+        whence: Whence,
+        _ctx: CallerContext,
+    ) -> Result<usize, i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&descriptor_id).ok_or(EBADF)?;
+
+        match whence {
+            Whence::Set => handle.offset = offset,
+            Whence::Current => handle.offset += offset,
+        }
+
+        Ok(handle.offset)
+    }
+
+    fn close(&self, descriptor_id: FileDescriptorId, ctx: CallerContext) -> Result<(), i32> {
+        HANDLES.write().remove(&descriptor_id).ok_or(EBADF)?;
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        task.remove_file(descriptor_id);
+
+        Ok(())
+    }
+
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get(&descriptor_id).ok_or(EBADF)?.clone();
+        handles.insert(new_id, handle);
+
+        Ok(())
+    }
+}
@@ -1,15 +1,26 @@
+mod cgroup;
 pub mod pipe;
 mod proc;
 pub mod ps2;
+mod random;
+mod sched_trace;
 mod schemes;
 mod serial;
+mod shm;
+mod sys;
 pub mod vga;
 
-use crate::sched::pid::Pid;
-use alloc::{boxed::Box, sync::Arc};
+use crate::{
+    memory::{address::PhysicalAddress, paging::PageFlags},
+    sched::pid::Pid,
+};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use hashbrown::HashMap;
 use lazy_static::lazy_static;
-use libjon::fd::{FileDescriptorFlags, FileDescriptorId};
+use libjon::{
+    errno::ENODEV,
+    fd::{FileDescriptorFlags, FileDescriptorId},
+};
 use log::debug;
 use spinning_top::{
     lock_api::{RwLockReadGuard, RwLockWriteGuard},
@@ -26,6 +37,8 @@ lazy_static! {
         list.add("vga", Arc::new(vga));
         debug!("Adding pipe scheme");
         list.add("pipe", Arc::new(pipe::PipeScheme));
+        debug!("Adding shm scheme");
+        list.add("shm", Arc::new(shm::ShmScheme));
         debug!("Adding serial scheme");
         list.add("serial", Arc::new(serial::SerialScheme));
         debug!("Adding ps2 scheme");
@@ -33,6 +46,14 @@ lazy_static! {
         list.add("ps2", Arc::new(ps2::Ps2Scheme));
         debug!("Adding proc scheme");
         list.add("proc", Arc::new(proc::ProcScheme));
+        debug!("Adding random scheme");
+        list.add("random", Arc::new(random::RandomScheme));
+        debug!("Adding sys scheme");
+        list.add("sys", Arc::new(sys::SysScheme));
+        debug!("Adding cgroup scheme");
+        list.add("cgroup", Arc::new(cgroup::CGroupScheme));
+        debug!("Adding sched-trace scheme");
+        list.add("sched-trace", Arc::new(sched_trace::SchedTraceScheme));
         RwSpinlock::new(list)
     };
 }
@@ -76,6 +97,10 @@ pub trait KernelScheme: Send + Sync + 'static {
 
     fn close(&self, descriptor_id: FileDescriptorId, ctx: CallerContext) -> Result<(), i32>;
 
+    /// Opens `new_id` on whatever `descriptor_id` has open, for a forked task's copy of
+    /// the descriptor
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32>;
+
     fn lseek(
         &self,
         descriptor_id: FileDescriptorId,
@@ -85,6 +110,18 @@ pub trait KernelScheme: Send + Sync + 'static {
     ) -> Result<usize, i32> {
         Err(38)
     }
+
+    /// Frames backing the first `size` bytes of what `descriptor_id` has open, for the
+    /// caller to map into its address space, and the caching flags they need
+    ///
+    /// Each frame comes with a reference taken for the caller's mapping.
+    fn mmap(
+        &self,
+        descriptor_id: FileDescriptorId,
+        size: usize,
+    ) -> Result<(Vec<PhysicalAddress>, PageFlags), i32> {
+        Err(ENODEV)
+    }
 }
 
 #[repr(i32)]
@@ -227,6 +227,36 @@ impl KernelScheme for PipeScheme {
 
         Ok(())
     }
+
+    /// The copy reads and writes the same pipe, which stays owned by the original's root
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        let mut fds = FDS.write();
+        let pipe_id = *fds.get(&descriptor_id).ok_or(EBADF)?;
+        let mut pipes = PIPES.write();
+        let pipe = pipes.get_mut(&pipe_id).ok_or(EINVAL)?;
+
+        if pipe.readers.contains(&descriptor_id) {
+            pipe.readers.push(new_id);
+        }
+        if pipe.writers.contains(&descriptor_id) {
+            pipe.writers.push(new_id);
+        }
+
+        fds.insert(new_id, pipe_id);
+
+        Ok(())
+    }
+}
+
+/// Queues a message on the pipe at `path` from inside the kernel, which has no descriptor
+/// to write through
+pub fn post(path: &str, message: &[u8]) -> Result<(), i32> {
+    let pipe_id = *PATHS.read().get(path).ok_or(ENOENT)?;
+    let mut pipes = PIPES.write();
+    let pipe = pipes.get_mut(&pipe_id).ok_or(ENOENT)?;
+    pipe.buffer.push_back(Vec::from(message));
+
+    Ok(())
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -1,8 +1,12 @@
-use crate::sched::{
-    fd::FileDescriptor,
-    pid::Pid,
-    scheduler::{get_task, get_task_mut, get_tasks},
-    task::{Priority, State},
+use crate::{
+    arch::x86::{cpu::online_cpus, timer::ticks_to_ms},
+    sched::{
+        fd::FileDescriptor,
+        pid::Pid,
+        reaper::{freed_frames, reaped, zombies},
+        scheduler::{get_task, get_task_mut, get_tasks, TASKS},
+        task::{Priority, State, Task},
+    },
 };
 
 use super::KernelScheme;
@@ -12,19 +16,63 @@ use libjon::fd::FileDescriptorId;
 use spinning_top::RwSpinlock;
 
 lazy_static! {
-    static ref HANDLES: RwSpinlock<BTreeMap<FileDescriptorId, usize>> =
+    static ref HANDLES: RwSpinlock<BTreeMap<FileDescriptorId, ProcHandle>> =
         RwSpinlock::new(BTreeMap::new());
 }
 
+#[derive(Debug, Clone, Copy)]
+enum ProcHandle {
+    All,
+    Task(usize),
+    /// Per-CPU scheduler statistics
+    Cpus,
+    /// Task table and reaper counters
+    Stats,
+}
+
 #[repr(C)]
 pub struct Proc {
     pub pid: usize,
+    /// 0 for tasks started by the kernel
+    pub ppid: usize,
+    pub pgid: usize,
+    pub sid: usize,
     pub name: [u8; 16],
     pub state: State,
     pub priority: Priority,
+    pub last_cpu: u64,
+    /// Times are in milliseconds
+    pub user_ms: u64,
+    pub system_ms: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    pub start_ms: u64,
 }
 
 impl Proc {
+    pub fn new(task: &Task) -> Self {
+        let mut name = [0u8; 16];
+        let len = task.name.len().min(15);
+        name[..len].copy_from_slice(&task.name.as_bytes()[..len]);
+        name[len] = 0; // null terminator
+
+        Self {
+            pid: task.pid.as_usize(),
+            ppid: task.parent.map_or(0, |pid| pid.as_usize()),
+            pgid: task.pgid.as_usize(),
+            sid: task.sid.as_usize(),
+            name,
+            state: task.state,
+            priority: task.priority,
+            last_cpu: task.stats.last_cpu,
+            user_ms: ticks_to_ms(task.stats.user_ticks),
+            system_ms: ticks_to_ms(task.stats.system_ticks),
+            voluntary_switches: task.stats.voluntary_switches,
+            involuntary_switches: task.stats.involuntary_switches,
+            start_ms: ticks_to_ms(task.stats.start_time),
+        }
+    }
+
     pub fn to_bytes(&self) -> &[u8] {
         unsafe {
             core::slice::from_raw_parts(
@@ -35,6 +83,50 @@ impl Proc {
     }
 }
 
+#[repr(C)]
+pub struct CpuStat {
+    pub id: u64,
+    pub lapic_id: u64,
+    pub run_queue_len: usize,
+    /// 0 when the CPU isn't running anything
+    pub current_pid: usize,
+    pub ticks: u64,
+    pub migrations: u64,
+    pub steals: u64,
+}
+
+#[repr(C)]
+pub struct ProcStats {
+    /// Entries in the task table, zombies included
+    pub tasks: usize,
+    /// Stopped tasks whose memory hasn't been freed yet
+    pub zombies: usize,
+    pub reaped: u64,
+    pub freed_frames: u64,
+}
+
+impl ProcStats {
+    pub fn to_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const ProcStats as *const u8,
+                core::mem::size_of::<ProcStats>(),
+            )
+        }
+    }
+}
+
+impl CpuStat {
+    pub fn to_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const CpuStat as *const u8,
+                core::mem::size_of::<CpuStat>(),
+            )
+        }
+    }
+}
+
 pub struct ProcScheme;
 
 impl KernelScheme for ProcScheme {
@@ -50,12 +142,13 @@ impl KernelScheme for ProcScheme {
         };
         let descriptor = FileDescriptor::new(ctx.scheme, flags);
         let id = descriptor.id;
-        let pid: usize = if path == "" {
-            0
-        } else {
-            path.parse().map_err(|_| libjon::errno::EINVAL)?
+        let handle = match path {
+            "" => ProcHandle::All,
+            "cpus" => ProcHandle::Cpus,
+            "stats" => ProcHandle::Stats,
+            _ => ProcHandle::Task(path.parse().map_err(|_| libjon::errno::EINVAL)?),
         };
-        HANDLES.write().insert(descriptor.id, pid);
+        HANDLES.write().insert(descriptor.id, handle);
         task.add_file(descriptor);
 
         Ok(id)
@@ -68,11 +161,51 @@ impl KernelScheme for ProcScheme {
         count: usize,
     ) -> Result<usize, i32> {
         let handles = HANDLES.read();
-        let pid = handles.get(&descriptor_id).ok_or(libjon::errno::ENOENT)?;
+        let handle = handles.get(&descriptor_id).ok_or(libjon::errno::ENOENT)?;
 
         let mut offset = 0;
 
-        if *pid == 0 {
+        let pid = match *handle {
+            ProcHandle::Task(pid) => pid,
+            ProcHandle::Cpus => {
+                for pcr in online_cpus() {
+                    let stat = CpuStat {
+                        id: pcr.id,
+                        lapic_id: pcr.lapic_id,
+                        run_queue_len: pcr.sched.run_queue.lock().len(),
+                        current_pid: pcr.sched.current_pid.map_or(0, |pid| pid.as_usize()),
+                        ticks: pcr.sched.pit_ticks,
+                        migrations: pcr.sched.migrations,
+                        steals: pcr.sched.steals,
+                    };
+                    let bytes = stat.to_bytes();
+
+                    if offset + bytes.len() > count || offset + bytes.len() > buf.len() {
+                        break;
+                    }
+                    buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+                    offset += bytes.len();
+                }
+
+                return Ok(offset);
+            }
+            ProcHandle::Stats => {
+                let stats = ProcStats {
+                    tasks: TASKS.read().len(),
+                    zombies: zombies(),
+                    reaped: reaped(),
+                    freed_frames: freed_frames(),
+                };
+                let bytes = stats.to_bytes();
+                let len = bytes.len().min(count).min(buf.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+
+                return Ok(len);
+            }
+            ProcHandle::All => 0,
+        };
+
+        if pid == 0 {
             let tasks = get_tasks();
 
             for task in tasks.iter() {
@@ -80,16 +213,7 @@ impl KernelScheme for ProcScheme {
                     continue;
                 }
 
-                let mut name_buf = [0u8; 16];
-                let len = task.name.len().min(15);
-                name_buf[..len].copy_from_slice(task.name.as_bytes());
-                name_buf[len] = 0; // null terminator
-                let proc = Proc {
-                    pid: task.pid.as_usize(),
-                    name: name_buf,
-                    state: task.state,
-                    priority: task.priority,
-                };
+                let proc = Proc::new(task);
                 let bytes = proc.to_bytes();
 
                 if offset + bytes.len() > count || offset + bytes.len() > buf.len() {
@@ -102,18 +226,9 @@ impl KernelScheme for ProcScheme {
             return Ok(offset);
         }
 
-        match get_task(Pid::new(*pid)) {
+        match get_task(Pid::new(pid)) {
             Some(task) => {
-                let mut name_buf = [0u8; 16];
-                let len = task.name.len().min(15);
-                name_buf[..len].copy_from_slice(task.name.as_bytes());
-                name_buf[len] = 0; // null terminator
-                let proc = Proc {
-                    pid: task.pid.as_usize(),
-                    name: name_buf,
-                    state: task.state,
-                    priority: task.priority,
-                };
+                let proc = Proc::new(task);
                 let bytes = proc.to_bytes();
 
                 if offset + bytes.len() > count || offset + bytes.len() > buf.len() {
@@ -148,4 +263,12 @@ impl KernelScheme for ProcScheme {
 
         Ok(())
     }
+
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        let mut handles = HANDLES.write();
+        let handle = *handles.get(&descriptor_id).ok_or(libjon::errno::ENOENT)?;
+        handles.insert(new_id, handle);
+
+        Ok(())
+    }
 }
@@ -106,4 +106,9 @@ impl KernelScheme for Ps2Scheme {
         task.remove_file(descriptor_id);
         Ok(())
     }
+
+    /// Descriptors keep no state, every one reads the same controller
+    fn dup(&self, _descriptor_id: FileDescriptorId, _new_id: FileDescriptorId) -> Result<(), i32> {
+        Ok(())
+    }
 }
@@ -0,0 +1,86 @@
+use alloc::collections::btree_set::BTreeSet;
+use libjon::{
+    errno::{EBADF, EINVAL},
+    fd::{FileDescriptorFlags, FileDescriptorId},
+};
+use log::debug;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    random::{self, POOL},
+    sched::{fd::FileDescriptor, scheduler::get_task_mut},
+};
+
+use super::{CallerContext, KernelScheme};
+
+static DESCRIPTORS: RwSpinlock<BTreeSet<FileDescriptorId>> = RwSpinlock::new(BTreeSet::new());
+
+#[derive(Debug)]
+pub struct RandomScheme;
+
+impl KernelScheme for RandomScheme {
+    fn open(
+        &self,
+        _path: &str,
+        flags: FileDescriptorFlags,
+        ctx: CallerContext,
+    ) -> Result<FileDescriptorId, i32> {
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        let descriptor = FileDescriptor::new(ctx.scheme, flags);
+        let id = descriptor.id;
+        DESCRIPTORS.write().insert(id);
+        task.add_file(descriptor);
+
+        Ok(id)
+    }
+
+    fn read(
+        &self,
+        descriptor_id: FileDescriptorId,
+        buf: &mut [u8],
+        count: usize,
+    ) -> Result<usize, i32> {
+        DESCRIPTORS.read().get(&descriptor_id).ok_or(EBADF)?;
+        let bytes_to_read = count.min(buf.len());
+        random::fill(&mut buf[..bytes_to_read]);
+
+        Ok(bytes_to_read)
+    }
+
+    /// Writing mixes the data into the entropy pool, like writing to /dev/random
+    fn write(
+        &self,
+        descriptor_id: FileDescriptorId,
+        buf: &[u8],
+        count: usize,
+    ) -> Result<usize, i32> {
+        DESCRIPTORS.read().get(&descriptor_id).ok_or(EBADF)?;
+        let bytes_to_write = count.min(buf.len());
+        let mut pool = POOL.lock();
+
+        for chunk in buf[..bytes_to_write].chunks(8) {
+            let mut bytes = [0u8; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            pool.add_entropy(u64::from_ne_bytes(bytes));
+        }
+
+        Ok(bytes_to_write)
+    }
+
+    fn close(&self, descriptor_id: FileDescriptorId, ctx: CallerContext) -> Result<(), i32> {
+        debug!("Closing fd: {:?}", descriptor_id);
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        task.remove_file(descriptor_id);
+        DESCRIPTORS.write().remove(&descriptor_id);
+
+        Ok(())
+    }
+
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        let mut descriptors = DESCRIPTORS.write();
+        descriptors.get(&descriptor_id).ok_or(EBADF)?;
+        descriptors.insert(new_id);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use alloc::{collections::btree_map::BTreeMap, string::String};
+use libjon::{
+    errno::{EBADF, EINVAL, ENOENT},
+    fd::{FileDescriptorFlags, FileDescriptorId},
+};
+use log::debug;
+use spinning_top::RwSpinlock;
+
+use crate::sched::{fd::FileDescriptor, scheduler::get_task_mut, trace};
+
+use super::{CallerContext, KernelScheme, Whence};
+
+static HANDLES: RwSpinlock<BTreeMap<FileDescriptorId, TraceHandle>> =
+    RwSpinlock::new(BTreeMap::new());
+
+#[derive(Debug, Clone)]
+struct TraceHandle {
+    /// Events as they were when the file was opened, they keep coming in meanwhile
+    content: String,
+    offset: usize,
+}
+
+/// Scheduler events of every CPU
+///
+/// Reading `sched-trace:` gives the events recorded up to when it was opened, see
+/// `trace::render` for the format. Tracing is controlled by writing one command at a time:
+///
+/// - `start` and `stop` turn recording on and off, it starts off
+/// - `clear` drops the events recorded so far
+/// - `dump` prints them to the serial port, for the host-side `sched-trace` tool
+#[derive(Debug)]
+pub struct SchedTraceScheme;
+
+impl KernelScheme for SchedTraceScheme {
+    fn open(
+        &self,
+        path: &str,
+        flags: FileDescriptorFlags,
+        ctx: CallerContext,
+    ) -> Result<FileDescriptorId, i32> {
+        debug!("Opening sched-trace: {}", path);
+
+        if !path.is_empty() {
+            return Err(ENOENT);
+        }
+
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        let descriptor = FileDescriptor::new(ctx.scheme, flags);
+        let id = descriptor.id;
+        HANDLES.write().insert(
+            id,
+            TraceHandle {
+                content: trace::render(),
+                offset: 0,
+            },
+        );
+        task.add_file(descriptor);
+
+        Ok(id)
+    }
+
+    fn read(
+        &self,
+        descriptor_id: FileDescriptorId,
+        buf: &mut [u8],
+        count: usize,
+    ) -> Result<usize, i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&descriptor_id).ok_or(EBADF)?;
+        let bytes = handle.content.as_bytes();
+
+        if handle.offset >= bytes.len() {
+            return Ok(0);
+        }
+
+        let bytes_to_read = count.min(buf.len()).min(bytes.len() - handle.offset);
+        buf[..bytes_to_read].copy_from_slice(&bytes[handle.offset..handle.offset + bytes_to_read]);
+        handle.offset += bytes_to_read;
+
+        Ok(bytes_to_read)
+    }
+
+    fn write(
+        &self,
+        descriptor_id: FileDescriptorId,
+        buf: &[u8],
+        count: usize,
+    ) -> Result<usize, i32> {
+        if !HANDLES.read().contains_key(&descriptor_id) {
+            return Err(EBADF);
+        }
+
+        let bytes_to_write = count.min(buf.len());
+        let command = String::from_utf8_lossy(&buf[..bytes_to_write]);
+
+        match command.trim() {
+            "start" => trace::set_enabled(true),
+            "stop" => trace::set_enabled(false),
+            "clear" => trace::clear(),
+            "dump" => trace::dump(),
+            _ => return Err(EINVAL),
+        }
+
+        Ok(bytes_to_write)
+    }
+
+    fn lseek(
+        &self,
+        descriptor_id: FileDescriptorId,
+        offset: usize,
+        whence: Whence,
+        _ctx: CallerContext,
+    ) -> Result<usize, i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&descriptor_id).ok_or(EBADF)?;
+
+        match whence {
+            Whence::Set => handle.offset = offset,
+            Whence::Current => handle.offset += offset,
+        }
+
+        Ok(handle.offset)
+    }
+
+    fn close(&self, descriptor_id: FileDescriptorId, ctx: CallerContext) -> Result<(), i32> {
+        HANDLES.write().remove(&descriptor_id).ok_or(EBADF)?;
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        task.remove_file(descriptor_id);
+
+        Ok(())
+    }
+
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get(&descriptor_id).ok_or(EBADF)?.clone();
+        handles.insert(new_id, handle);
+
+        Ok(())
+    }
+}
@@ -43,4 +43,13 @@ impl KernelScheme for SchemesScheme {
     ) -> Result<(), i32> {
         todo!()
     }
+
+    /// Nothing can be opened here yet, so there is never a descriptor to copy
+    fn dup(
+        &self,
+        _descriptor_id: libjon::fd::FileDescriptorId,
+        _new_id: libjon::fd::FileDescriptorId,
+    ) -> Result<(), i32> {
+        Err(libjon::errno::EBADF)
+    }
 }
@@ -79,4 +79,12 @@ impl KernelScheme for SerialScheme {
 
         Ok(())
     }
+
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        let mut descriptors = DESCRIPTORS.write();
+        descriptors.get(&descriptor_id).ok_or(EINVAL)?;
+        descriptors.insert(new_id);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,177 @@
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, vec::Vec};
+use libjon::{
+    errno::{EBADF, EEXIST, EINVAL, ENOENT, ENOMEM},
+    fd::{FileDescriptorFlags, FileDescriptorId},
+};
+use log::debug;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    arch::x86::memory::PMM,
+    memory::{
+        address::PhysicalAddress,
+        paging::{phys_to_virt, PageFlags},
+        physical::{FrameOwner, PhysicalMemoryManager},
+        PAGE_SIZE,
+    },
+    sched::{fd::FileDescriptor, scheduler::get_task_mut},
+};
+
+use super::{CallerContext, KernelScheme};
+
+static REGIONS: RwSpinlock<BTreeMap<Box<str>, SharedRegion>> = RwSpinlock::new(BTreeMap::new());
+static FDS: RwSpinlock<BTreeMap<FileDescriptorId, Box<str>>> = RwSpinlock::new(BTreeMap::new());
+
+#[derive(Debug, Default)]
+struct SharedRegion {
+    /// Empty until the first `mmap` sizes the region
+    frames: Vec<PhysicalAddress>,
+    /// Descriptors open on the region, it goes away with the last one
+    descriptors: usize,
+}
+
+/// Named regions of memory that tasks map into their address spaces to share data
+/// without copying it
+///
+/// `shm:name` with `O_CREAT` creates the region, which gets its pages on the first
+/// `SYS_MMAP` of it, later ones map the same pages and can't ask for more. Every mapping
+/// keeps the pages it maps alive, so they are only freed once the last descriptor is
+/// closed and the last mapping is gone.
+#[derive(Debug)]
+pub struct ShmScheme;
+
+impl KernelScheme for ShmScheme {
+    fn open(
+        &self,
+        path: &str,
+        flags: FileDescriptorFlags,
+        ctx: CallerContext,
+    ) -> Result<FileDescriptorId, i32> {
+        debug!("Opening shm: {}", path);
+
+        if path.is_empty() {
+            return Err(ENOENT);
+        }
+
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        let mut regions = REGIONS.write();
+        let exists = regions.contains_key(path);
+
+        if exists && flags.contains(FileDescriptorFlags::O_CREAT | FileDescriptorFlags::O_EXCL) {
+            return Err(EEXIST);
+        }
+        if !exists && !flags.contains(FileDescriptorFlags::O_CREAT) {
+            return Err(ENOENT);
+        }
+
+        let region = regions.entry(path.into()).or_default();
+
+        let descriptor = FileDescriptor::new(ctx.scheme, flags);
+        let id = descriptor.id;
+        region.descriptors += 1;
+        FDS.write().insert(id, path.into());
+        task.add_file(descriptor);
+
+        Ok(id)
+    }
+
+    /// The region's contents are only reachable through `mmap`
+    fn read(
+        &self,
+        descriptor_id: FileDescriptorId,
+        _buf: &mut [u8],
+        _count: usize,
+    ) -> Result<usize, i32> {
+        FDS.read().get(&descriptor_id).ok_or(EBADF)?;
+
+        Err(EINVAL)
+    }
+
+    fn write(
+        &self,
+        descriptor_id: FileDescriptorId,
+        _buf: &[u8],
+        _count: usize,
+    ) -> Result<usize, i32> {
+        FDS.read().get(&descriptor_id).ok_or(EBADF)?;
+
+        Err(EINVAL)
+    }
+
+    fn close(&self, descriptor_id: FileDescriptorId, _ctx: CallerContext) -> Result<(), i32> {
+        let name = FDS.write().remove(&descriptor_id).ok_or(EBADF)?;
+        let mut regions = REGIONS.write();
+        let region = regions.get_mut(&name).ok_or(ENOENT)?;
+        region.descriptors -= 1;
+
+        if region.descriptors == 0 {
+            // frames still mapped somewhere keep the references of their mappings
+            let region = regions.remove(&name).unwrap();
+            let mut pmm = PMM.lock();
+
+            for frame in region.frames {
+                pmm.free(frame);
+            }
+
+            debug!("Removed shared region {}", name);
+        }
+
+        Ok(())
+    }
+
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        // `open` locks the regions before the descriptors, so never hold both the other way
+        let name = FDS.read().get(&descriptor_id).ok_or(EBADF)?.clone();
+        REGIONS.write().get_mut(&name).ok_or(ENOENT)?.descriptors += 1;
+        FDS.write().insert(new_id, name);
+
+        Ok(())
+    }
+
+    fn mmap(
+        &self,
+        descriptor_id: FileDescriptorId,
+        size: usize,
+    ) -> Result<(Vec<PhysicalAddress>, PageFlags), i32> {
+        let pages = size.div_ceil(PAGE_SIZE);
+
+        if pages == 0 {
+            return Err(EINVAL);
+        }
+
+        let name = FDS.read().get(&descriptor_id).ok_or(EBADF)?.clone();
+        let mut regions = REGIONS.write();
+        let region = regions.get_mut(&name).ok_or(ENOENT)?;
+        let mut pmm = PMM.lock();
+
+        if region.frames.is_empty() {
+            for _ in 0..pages {
+                let Ok(frame) = pmm.allocate(FrameOwner::Shared) else {
+                    for frame in region.frames.drain(..) {
+                        pmm.free(frame);
+                    }
+                    return Err(ENOMEM);
+                };
+
+                unsafe {
+                    core::ptr::write_bytes(phys_to_virt(frame.as_usize()) as *mut u8, 0, PAGE_SIZE)
+                };
+                region.frames.push(frame);
+            }
+
+            debug!("Shared region {} has {} pages", name, pages);
+        }
+
+        if pages > region.frames.len() {
+            return Err(EINVAL);
+        }
+
+        let frames = region.frames[..pages].to_vec();
+
+        for frame in frames.iter() {
+            pmm.share(*frame);
+        }
+
+        Ok((frames, PageFlags::empty()))
+    }
+}
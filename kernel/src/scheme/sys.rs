@@ -0,0 +1,237 @@
+use core::fmt::Write;
+
+use alloc::{collections::btree_map::BTreeMap, string::String};
+use libjon::{
+    errno::{EBADF, EINVAL, ENOENT},
+    fd::{FileDescriptorFlags, FileDescriptorId},
+};
+use limine::memory_map::EntryType;
+use log::debug;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    arch::x86::{cpu::online_cpus, memory::PMM},
+    memory::{physical::PhysicalMemoryManager, MEMORY_MAP},
+    sched::{
+        fd::FileDescriptor,
+        scheduler::{get_task_mut, uptime_ms},
+        watchdog,
+    },
+};
+
+use super::{CallerContext, KernelScheme, Whence};
+
+static HANDLES: RwSpinlock<BTreeMap<FileDescriptorId, SysHandle>> =
+    RwSpinlock::new(BTreeMap::new());
+
+#[cfg(target_arch = "x86_64")]
+const ARCH: &str = "x86_64";
+
+const ENTRY_TYPES: [(EntryType, &str); 8] = [
+    (EntryType::USABLE, "usable"),
+    (EntryType::RESERVED, "reserved"),
+    (EntryType::ACPI_RECLAIMABLE, "acpi-reclaimable"),
+    (EntryType::ACPI_NVS, "acpi-nvs"),
+    (EntryType::BAD_MEMORY, "bad-memory"),
+    (EntryType::BOOTLOADER_RECLAIMABLE, "bootloader-reclaimable"),
+    (EntryType::KERNEL_AND_MODULES, "kernel-and-modules"),
+    (EntryType::FRAMEBUFFER, "framebuffer"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SysEntry {
+    /// Lists the available entries
+    Root,
+    Version,
+    Cpus,
+    Uptime,
+    MemoryMap,
+    MemoryInfo,
+    /// Frames held by each owner and how many frees went wrong
+    Frames,
+    /// Lockup thresholds and counts, writing `restart on` or `restart off` toggles
+    /// restarting stuck tasks
+    Watchdog,
+}
+
+#[derive(Debug, Clone)]
+struct SysHandle {
+    entry: SysEntry,
+    offset: usize,
+}
+
+impl SysEntry {
+    fn from_path(path: &str) -> Option<Self> {
+        match path {
+            "" => Some(Self::Root),
+            "version" => Some(Self::Version),
+            "cpus" => Some(Self::Cpus),
+            "uptime" => Some(Self::Uptime),
+            "memmap" => Some(Self::MemoryMap),
+            "meminfo" => Some(Self::MemoryInfo),
+            "frames" => Some(Self::Frames),
+            "watchdog" => Some(Self::Watchdog),
+            _ => None,
+        }
+    }
+
+    /// Renders the entry, every read sees a fresh snapshot
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        match self {
+            SysEntry::Root => {
+                for name in [
+                    "version", "cpus", "uptime", "memmap", "meminfo", "frames", "watchdog",
+                ] {
+                    writeln!(out, "{}", name).unwrap();
+                }
+            }
+            SysEntry::Version => {
+                writeln!(out, "JonOS {} ({})", env!("CARGO_PKG_VERSION"), ARCH).unwrap();
+            }
+            SysEntry::Cpus => {
+                for pcr in online_cpus() {
+                    writeln!(out, "{} {}", pcr.id, pcr.lapic_id).unwrap();
+                }
+            }
+            SysEntry::Uptime => {
+                writeln!(out, "{}", uptime_ms()).unwrap();
+            }
+            SysEntry::MemoryMap => {
+                for entry in MEMORY_MAP.iter() {
+                    let name = ENTRY_TYPES
+                        .iter()
+                        .find(|(entry_type, _)| *entry_type == entry.entry_type)
+                        .map(|(_, name)| *name)
+                        .unwrap_or("unknown");
+                    writeln!(
+                        out,
+                        "{:#018x} {:#018x} {}",
+                        entry.base,
+                        entry.base + entry.length,
+                        name
+                    )
+                    .unwrap();
+                }
+            }
+            SysEntry::MemoryInfo => {
+                let pmm = PMM.lock();
+                let total = pmm.total_memory() / 1024;
+                let available = pmm.available_memory() / 1024;
+                writeln!(out, "total: {} KiB", total).unwrap();
+                writeln!(out, "available: {} KiB", available).unwrap();
+                writeln!(out, "used: {} KiB", total - available).unwrap();
+            }
+            SysEntry::Frames => {
+                let pmm = PMM.lock();
+
+                for (owner, frames) in pmm.usage() {
+                    writeln!(out, "{} {}", owner, frames).unwrap();
+                }
+
+                writeln!(out, "bad-frees {}", pmm.bad_frees()).unwrap();
+            }
+            SysEntry::Watchdog => out.push_str(&watchdog::render()),
+        }
+
+        out
+    }
+}
+
+#[derive(Debug)]
+pub struct SysScheme;
+
+impl KernelScheme for SysScheme {
+    fn open(
+        &self,
+        path: &str,
+        flags: FileDescriptorFlags,
+        ctx: CallerContext,
+    ) -> Result<FileDescriptorId, i32> {
+        debug!("Opening sys entry: {}", path);
+        let entry = SysEntry::from_path(path).ok_or(ENOENT)?;
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        let descriptor = FileDescriptor::new(ctx.scheme, flags);
+        let id = descriptor.id;
+        HANDLES.write().insert(id, SysHandle { entry, offset: 0 });
+        task.add_file(descriptor);
+
+        Ok(id)
+    }
+
+    fn read(
+        &self,
+        descriptor_id: FileDescriptorId,
+        buf: &mut [u8],
+        count: usize,
+    ) -> Result<usize, i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&descriptor_id).ok_or(EBADF)?;
+        let content = handle.entry.render();
+        let bytes = content.as_bytes();
+
+        if handle.offset >= bytes.len() {
+            return Ok(0);
+        }
+
+        let bytes_to_read = count.min(buf.len()).min(bytes.len() - handle.offset);
+        buf[..bytes_to_read].copy_from_slice(&bytes[handle.offset..handle.offset + bytes_to_read]);
+        handle.offset += bytes_to_read;
+
+        Ok(bytes_to_read)
+    }
+
+    fn write(
+        &self,
+        descriptor_id: FileDescriptorId,
+        buf: &[u8],
+        count: usize,
+    ) -> Result<usize, i32> {
+        let entry = HANDLES.read().get(&descriptor_id).ok_or(EBADF)?.entry;
+        let bytes_to_write = count.min(buf.len());
+        let command = String::from_utf8_lossy(&buf[..bytes_to_write]);
+
+        match (entry, command.trim()) {
+            (SysEntry::Watchdog, "restart on") => watchdog::set_restart(true),
+            (SysEntry::Watchdog, "restart off") => watchdog::set_restart(false),
+            _ => return Err(EINVAL),
+        }
+
+        Ok(bytes_to_write)
+    }
+
+    fn lseek(
+        &self,
+        descriptor_id: FileDescriptorId,
+        offset: usize,
+        whence: Whence,
+        _ctx: CallerContext,
+    ) -> Result<usize, i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&descriptor_id).ok_or(EBADF)?;
+
+        match whence {
+            Whence::Set => handle.offset = offset,
+            Whence::Current => handle.offset += offset,
+        }
+
+        Ok(handle.offset)
+    }
+
+    fn close(&self, descriptor_id: FileDescriptorId, ctx: CallerContext) -> Result<(), i32> {
+        HANDLES.write().remove(&descriptor_id).ok_or(EBADF)?;
+        let task = get_task_mut(ctx.pid).ok_or(EINVAL)?;
+        task.remove_file(descriptor_id);
+
+        Ok(())
+    }
+
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get(&descriptor_id).ok_or(EBADF)?.clone();
+        handles.insert(new_id, handle);
+
+        Ok(())
+    }
+}
@@ -9,9 +9,18 @@ use limine::request::FramebufferRequest;
 use log::{debug, info};
 use spinning_top::RwSpinlock;
 
-use crate::sched::{
-    fd::FileDescriptor,
-    scheduler::{self, get_task_mut},
+use crate::{
+    arch::x86::memory::PMM,
+    memory::{
+        address::PhysicalAddress,
+        paging::{align_up, PageFlags},
+        physical::PhysicalMemoryManager,
+        MEMORY_OFFSET, PAGE_SIZE,
+    },
+    sched::{
+        fd::FileDescriptor,
+        scheduler::{self, get_task_mut},
+    },
 };
 
 use super::{CallerContext, KernelScheme, Whence};
@@ -175,4 +184,43 @@ impl KernelScheme for VgaScheme {
 
         Ok(())
     }
+
+    fn dup(&self, descriptor_id: FileDescriptorId, new_id: FileDescriptorId) -> Result<(), i32> {
+        let mut descriptors = DESCRIPTORS.write();
+        let index = *descriptors.get(&descriptor_id).ok_or(EINVAL)?;
+        descriptors.insert(new_id, index);
+
+        Ok(())
+    }
+
+    /// The framebuffer's own pages, so the caller draws straight to the screen
+    ///
+    /// The scheme keeps the first reference to them for good, so unmapping never hands
+    /// them to the allocator.
+    fn mmap(
+        &self,
+        descriptor_id: FileDescriptorId,
+        size: usize,
+    ) -> Result<(Vec<PhysicalAddress>, PageFlags), i32> {
+        let index = *DESCRIPTORS.read().get(&descriptor_id).ok_or(EINVAL)?;
+        let framebuffers = self.framebuffers.read();
+        let framebuffer = framebuffers.get(index.0).ok_or(ENOENT)?;
+
+        if size == 0 || size > align_up(framebuffer.inner.len(), PAGE_SIZE) {
+            return Err(EINVAL);
+        }
+
+        let start = framebuffer.inner.as_ptr() as usize - *MEMORY_OFFSET as usize;
+        let mut pmm = PMM.lock();
+        let frames = (start..start + size)
+            .step_by(PAGE_SIZE)
+            .map(|address| {
+                let frame = PhysicalAddress::new(address);
+                pmm.share(frame);
+                frame
+            })
+            .collect();
+
+        Ok((frames, PageFlags::WRITE_COMBINING))
+    }
 }
@@ -3,29 +3,34 @@ use core::{arch::naked_asm, mem::offset_of};
 use crate::{
     arch::x86::{
         cpu::{ProcessorControlRegion, PCRS},
-        memory::{PMM, VMM},
-        structures::Scratch,
+        memory::PMM,
+        structures::{Preserved, Registers, Scratch},
+        timer::now,
     },
     memory::{address::VirtualAddress, paging::PageFlags, physical::PhysicalMemoryManager},
-    pop_scratch, push_scratch,
+    pop_preserved, pop_scratch, push_preserved, push_scratch, random,
     sched::{
+        group::{get_pgid, group_members, set_pgid, set_sid},
+        memory::{MemoryAreaType, HEAP_SIZE, HEAP_START},
         pid::Pid,
+        reaper::reap,
         scheduler::{
-            add_task, current_pid, current_task, current_task_mut, get_task, get_task_mut,
-            remove_current_task, remove_task, TASKS,
+            add_task, current_pid, current_task, current_task_mut, exit_current, remove_task,
+            set_deadline, TASKS,
         },
-        task::{State, Task},
+        task::{ExitReason, State, Task},
     },
     scheme::{schemes, CallerContext},
+    timer::sleep,
 };
-use alloc::sync::Arc;
 use libjon::{
-    errno::{EINTR, EINVAL, ENOENT, ENOMEM, ESRCH},
-    fd::{FileDescriptorFlags, FileDescriptorId},
+    errno::{EBADF, EFAULT, EINTR, EINVAL, ENOENT, ENOMEM, ESRCH},
+    fd::FileDescriptorFlags,
     path::Path,
     syscall::{
-        SYS_BRK, SYS_CLOSE, SYS_EXIT, SYS_GETPID, SYS_KILL, SYS_LSEEK, SYS_OPEN, SYS_READ,
-        SYS_SPAWN, SYS_WRITE,
+        SYS_BRK, SYS_CLOSE, SYS_EXIT, SYS_FORK, SYS_GETPGID, SYS_GETPID, SYS_GETRANDOM, SYS_KILL,
+        SYS_LSEEK, SYS_MMAP, SYS_MUNMAP, SYS_OPEN, SYS_READ, SYS_SCHED_SETATTR, SYS_SETPGID,
+        SYS_SETSID, SYS_SLEEP, SYS_SPAWN, SYS_WRITE,
     },
 };
 use log::{debug, error, info, warn};
@@ -40,6 +45,18 @@ use x86_64::{
 
 type SyscallResult = Result<usize, i32>;
 
+/// What `syscall_instruction` leaves on the task's kernel stack
+#[repr(C)]
+pub struct SyscallFrame {
+    pub scratch: Scratch,
+    /// Saved so a forked task can start from the same user registers
+    pub preserved: Preserved,
+    /// `rcx` and `r11` as `syscall` set them
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
 pub(super) fn init(cpu_id: u32) {
     let pcr = unsafe { PCRS.get_mut(cpu_id as usize).unwrap() };
     // Enable syscall/sysret
@@ -77,24 +94,29 @@ pub unsafe extern "C" fn syscall_instruction() {
         "swapgs;",                    // Swap KGSBASE with GSBASE, allowing fast TSS access.
         "mov gs:[{sp}], rsp;",        // Save userspace stack pointer
         "mov rsp, gs:[{ksp}];",       // Load kernel stack pointer
+        // Keep the userspace stack pointer on the task's kernel stack, the task might block
+        // and another one would overwrite the per-CPU slot
+        "push QWORD PTR gs:[{sp}];",
 
         "push r11;",
         "push rcx;",
 
         // Push context registers
+        push_preserved!(),
         push_scratch!(),
 
         "mov rdi, rsp;",
         "call {handler};",
 
         pop_scratch!(),
-
-        // Restore user GSBASE by swapping GSBASE and KGSBASE.
-        "swapgs;",
+        pop_preserved!(),
 
         "pop rcx",
         "pop r11;",
-        "mov rsp, gs:[{sp}];",        // Restore userspace stack pointer
+        "pop rsp;",                   // Restore userspace stack pointer
+
+        // Restore user GSBASE by swapping GSBASE and KGSBASE.
+        "swapgs;",
         "sysretq;",                 // Return into userspace; RCX=>RIP,R11=>RFLAGS
         handler = sym handle_syscall,
         sp = const(offset_of!(ProcessorControlRegion, user_rsp)),
@@ -102,8 +124,8 @@ pub unsafe extern "C" fn syscall_instruction() {
     );
 }
 
-pub unsafe extern "C" fn handle_syscall(registers: *mut Scratch) {
-    let scratch = &(*registers);
+pub unsafe extern "C" fn handle_syscall(frame: *mut SyscallFrame) {
+    let scratch = &(*frame).scratch;
     let (syscall_number, arg1, arg2, arg3, _arg4, _arg5, _arg6) = (
         scratch.rax as usize,
         scratch.rdi as usize,
@@ -116,11 +138,13 @@ pub unsafe extern "C" fn handle_syscall(registers: *mut Scratch) {
 
     debug!("Syscall {} received", syscall_number);
 
-    if let Some(current_task) = current_task() {
+    if let Some(current_task) = current_task_mut() {
         if current_task.state == State::Stopped {
-            (*registers).rax = -EINVAL as u64;
+            (*frame).scratch.rax = -EINVAL as u64;
             return;
         }
+
+        current_task.stats.yielded(now());
     }
 
     let result = match syscall_number {
@@ -134,6 +158,15 @@ pub unsafe extern "C" fn handle_syscall(registers: *mut Scratch) {
         SYS_KILL => sys_kill(arg1),
         SYS_SPAWN => sys_spawn(arg1),
         SYS_CLOSE => sys_close(arg1),
+        SYS_GETRANDOM => sys_getrandom(arg1, arg2),
+        SYS_SCHED_SETATTR => sys_sched_setattr(arg1, arg2, arg3),
+        SYS_SLEEP => sys_sleep(arg1),
+        SYS_SETPGID => sys_setpgid(arg1, arg2),
+        SYS_GETPGID => sys_getpgid(arg1),
+        SYS_SETSID => sys_setsid(),
+        SYS_FORK => sys_fork(&*frame),
+        SYS_MMAP => sys_mmap(arg1, arg2),
+        SYS_MUNMAP => sys_munmap(arg1),
         _ => {
             error!("Invalid syscall number: {}", syscall_number);
             Err(ENOENT)
@@ -143,20 +176,18 @@ pub unsafe extern "C" fn handle_syscall(registers: *mut Scratch) {
     match result {
         Ok(result) => {
             debug!("Syscall {} returned: {}", syscall_number, result);
-            (*registers).rax = result as u64;
+            (*frame).scratch.rax = result as u64;
         }
         Err(errno) => {
             debug!("Syscall {} failed: {}", syscall_number, errno);
-            (*registers).rax = -errno as u64;
+            (*frame).scratch.rax = -errno as u64;
         }
     }
 }
 
 fn sys_exit(code: usize) -> SyscallResult {
     debug!("Exiting with code: {}", code);
-    remove_current_task();
-
-    Ok(0)
+    exit_current(ExitReason::Exited(code))
 }
 
 fn sys_open(path_ptr: usize, path_len: usize, flags: usize) -> SyscallResult {
@@ -187,7 +218,11 @@ fn sys_open(path_ptr: usize, path_len: usize, flags: usize) -> SyscallResult {
         ) {
             Ok(fd_id) => {
                 debug!("Opened file descriptor: {:?}", fd_id);
-                Ok(fd_id.0)
+                // the scheme returns its handle, the task gets its own number for it
+                current_task()
+                    .and_then(|task| task.fds.iter().find(|desc| desc.id == fd_id))
+                    .map(|desc| desc.number)
+                    .ok_or(EBADF)
             }
             Err(err) => {
                 debug!("Error opening file: {}", err);
@@ -205,7 +240,7 @@ fn sys_read(fd: usize, buf_ptr: usize, count: usize) -> SyscallResult {
     let fd = task
         .fds
         .iter()
-        .find(|desc| desc.id == FileDescriptorId(fd))
+        .find(|desc| desc.number == fd)
         .ok_or(EINTR)?;
     let schemes = schemes();
     let scheme = schemes.get(fd.scheme).expect("ERROR: SCHEME NO REGISTERED");
@@ -221,7 +256,7 @@ fn sys_write(fd: usize, buf_ptr: usize, count: usize) -> SyscallResult {
     let fd = task
         .fds
         .iter()
-        .find(|desc| desc.id == FileDescriptorId(fd))
+        .find(|desc| desc.number == fd)
         .ok_or(EINTR)?;
     debug!("Found fd: {:?} in task", fd);
     let schemes = schemes();
@@ -243,7 +278,7 @@ fn sys_lseek(descriptor_id: usize, offset: usize, whence: usize) -> SyscallResul
     let fd = task
         .fds
         .iter()
-        .find(|desc| desc.id == FileDescriptorId(descriptor_id))
+        .find(|desc| desc.number == descriptor_id)
         .ok_or(EINTR)?;
     let ctx = CallerContext {
         pid: task.pid,
@@ -263,27 +298,28 @@ fn sys_brk(increment: usize) -> SyscallResult {
         return Err(EINVAL);
     }
 
-    let brk_start = VirtualAddress::new(0x6000_0000 + (10 * 1024 * 1024 * task.pid.as_usize()));
+    let brk_start = VirtualAddress::new(HEAP_START);
 
-    if increment > 10 * 1024 * 1024 {
+    if increment > HEAP_SIZE {
         return Err(ENOMEM);
     }
 
-    let phys = PMM
-        .lock()
-        .allocate_contiguous(increment)
-        .map_err(|_| ENOMEM)?;
-    VMM.lock()
-        .map_range(
+    // backed a page at a time as the task touches it
+    task.memory_descriptor
+        .reserve(
             brk_start,
-            phys,
             increment,
-            PageFlags::WRITABLE | PageFlags::USER_ACCESSIBLE | PageFlags::PRESENT,
+            PageFlags::WRITABLE
+                | PageFlags::USER_ACCESSIBLE
+                | PageFlags::PRESENT
+                | PageFlags::NO_EXECUTE,
+            MemoryAreaType::Heap,
         )
         .map_err(|_| ENOMEM)?;
 
     let new_brk = task.memory_descriptor.brk + increment as u64;
     task.memory_descriptor.brk = new_brk;
+    task.memory_descriptor.start_brk = brk_start.as_u64();
 
     Ok(brk_start.as_usize())
 }
@@ -294,7 +330,7 @@ fn sys_close(fd: usize) -> SyscallResult {
     let fd = task
         .fds
         .iter()
-        .find(|desc| desc.id == FileDescriptorId(fd))
+        .find(|desc| desc.number == fd)
         .ok_or(EINTR)?;
     let schemes = schemes();
     let scheme = schemes.get(fd.scheme).expect("ERROR: SCHEME NO REGISTERED");
@@ -312,9 +348,43 @@ fn sys_close(fd: usize) -> SyscallResult {
     }
 }
 
+/// Kills a task, or with a negative PID every task in that process group but the caller
 fn sys_kill(pid: usize) -> SyscallResult {
-    info!("Got kill syscall for PID {}", pid);
-    let pid = Pid::new(pid);
+    info!("Got kill syscall for PID {}", pid as isize);
+
+    match pid as isize {
+        pgid if pgid < 0 => kill_group(Pid::new(pgid.unsigned_abs())),
+        _ => kill(Pid::new(pid)),
+    }
+}
+
+/// Returns how many tasks were killed, or the first error once every member was tried
+fn kill_group(pgid: Pid) -> SyscallResult {
+    let current_pid = current_pid().expect("ERROR: NO CURRENT PID");
+    let members = group_members(pgid);
+
+    if members.is_empty() {
+        error!("ERROR: process group {} NOT FOUND", pgid);
+        return Err(ESRCH);
+    }
+
+    let mut killed = 0;
+    let mut first_error = None;
+
+    // one member failing doesn't spare the rest of the group
+    for pid in members.into_iter().filter(|&pid| pid != current_pid) {
+        match kill(pid) {
+            Ok(count) => killed += count,
+            Err(errno) => {
+                first_error.get_or_insert(errno);
+            }
+        }
+    }
+
+    first_error.map_or(Ok(killed), Err)
+}
+
+fn kill(pid: Pid) -> SyscallResult {
     let current_pid = current_pid().expect("ERROR: NO CURRENT PID");
 
     let task_exists = {
@@ -332,43 +402,140 @@ fn sys_kill(pid: usize) -> SyscallResult {
         return Err(EINVAL);
     }
 
-    let fds_to_close = {
-        let task = get_task(pid).expect("Task disappeared");
-        task.fds.clone()
-    };
-
-    for fd in fds_to_close {
-        let scheme = {
-            let schemes = schemes();
-            schemes.get(fd.scheme).map(|s| Arc::new(s))
-        };
-
-        info!("Closing fd {:?} for PID {}", fd.id, pid);
-        if let Some(scheme) = scheme {
-            if let Err(e) = scheme.close(fd.id, CallerContext::new(pid, fd.scheme)) {
-                warn!("Failed to close fd {:?} for PID {}: {}", fd.id, pid, e);
-            }
-        }
-
-        let task = get_task_mut(pid).ok_or(ESRCH)?;
-        task.fds.clear();
-    }
     info!("Removing task {}", pid);
 
     let found = remove_task(pid);
+    // frees it right away unless it's still running on another CPU
+    reap();
 
     Ok(found.into())
 }
 
 fn sys_spawn(index: usize) -> SyscallResult {
-    let task = match index {
+    let mut task = match index {
         0..=1 => return Err(EINVAL),
-        2 => Task::random(),
-        3 => Task::random_echo(),
+        2 => Task::random()?,
+        3 => Task::random_echo()?,
         _ => return Err(EINVAL),
     };
     let pid = task.pid;
+
+    // the new task starts out in the caller's group and session
+    if let Some(parent) = current_task() {
+        task.parent = Some(parent.pid);
+        task.pgid = parent.pgid;
+        task.sid = parent.sid;
+    }
+
     add_task(task);
 
     Ok(pid.as_usize())
 }
+
+/// Starts a copy of the caller, which returns to user mode from this same syscall with 0
+fn sys_fork(frame: &SyscallFrame) -> SyscallResult {
+    let parent = current_task_mut().ok_or(EINTR)?;
+    let mut registers = Registers::new();
+    registers.scratch = frame.scratch.clone();
+    registers.scratch.rax = 0;
+    registers.preserved = frame.preserved.clone();
+    registers.iret.rip = frame.rip;
+    registers.iret.rflags = frame.rflags;
+    registers.iret.rsp = frame.rsp;
+
+    let child = parent.fork(registers)?;
+    let pid = child.pid;
+    add_task(child);
+
+    Ok(pid.as_usize())
+}
+
+/// Maps the first `size` bytes of what `fd` has open into the caller, writable if the
+/// descriptor was opened for writing, returns where the mapping starts
+fn sys_mmap(fd: usize, size: usize) -> SyscallResult {
+    let task = current_task_mut().ok_or(EINTR)?;
+    let fd = task
+        .fds
+        .iter()
+        .find(|desc| desc.number == fd)
+        .ok_or(EBADF)?;
+    let (id, scheme_id, fd_flags) = (fd.id, fd.scheme, fd.flags);
+    let schemes = schemes();
+    let scheme = schemes.get(scheme_id).expect("ERROR: SCHEME NO REGISTERED");
+    let (frames, cache) = scheme.mmap(id, size)?;
+
+    let mut flags = PageFlags::PRESENT | PageFlags::USER_ACCESSIBLE | PageFlags::NO_EXECUTE | cache;
+    if fd_flags.contains(FileDescriptorFlags::O_WRONLY) {
+        flags |= PageFlags::WRITABLE;
+    }
+
+    match task.memory_descriptor.map_shared(&frames, flags) {
+        Ok(address) => Ok(address.as_usize()),
+        Err(_) => {
+            let mut pmm = PMM.lock();
+            for frame in frames {
+                pmm.free(frame);
+            }
+            Err(ENOMEM)
+        }
+    }
+}
+
+/// Removes a mapping `SYS_MMAP` made, `address` being where it starts
+fn sys_munmap(address: usize) -> SyscallResult {
+    let task = current_task_mut().ok_or(EINTR)?;
+
+    task.memory_descriptor
+        .unmap_shared(VirtualAddress::new(address))
+        .map(|_| 0)
+        .map_err(|_| EINVAL)
+}
+
+fn sys_getrandom(buf_ptr: usize, count: usize) -> SyscallResult {
+    if buf_ptr == 0 {
+        return Err(EINVAL);
+    }
+
+    let task = current_task().ok_or(EINTR)?;
+
+    if !task
+        .memory_descriptor
+        .contains_range(VirtualAddress::new(buf_ptr), count, true)
+    {
+        return Err(EFAULT);
+    }
+
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf_ptr as *mut u8, count) };
+    random::fill(buf);
+
+    Ok(count)
+}
+
+/// Puts the caller in the deadline class, times are in milliseconds and a period of 0 goes
+/// back to the normal class
+fn sys_sched_setattr(period: usize, runtime: usize, deadline: usize) -> SyscallResult {
+    set_deadline(period as u64, runtime as u64, deadline as u64)?;
+
+    Ok(0)
+}
+
+fn sys_setpgid(pid: usize, pgid: usize) -> SyscallResult {
+    set_pgid(pid, pgid)?;
+
+    Ok(0)
+}
+
+fn sys_getpgid(pid: usize) -> SyscallResult {
+    get_pgid(pid).map(|pgid| pgid.as_usize())
+}
+
+fn sys_setsid() -> SyscallResult {
+    set_sid().map(|sid| sid.as_usize())
+}
+
+/// Blocks the caller for at least `ms` milliseconds
+fn sys_sleep(ms: usize) -> SyscallResult {
+    sleep(ms as u64);
+
+    Ok(0)
+}
@@ -0,0 +1,127 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+
+use crate::{
+    arch::x86::{
+        cpu::{current_pcr, get_pcr},
+        timer::{ms_to_ticks, now},
+    },
+    sched::{
+        pid::Pid,
+        scheduler::{block_current, prepare_to_block},
+    },
+};
+
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId {
+    id: u64,
+    /// CPU whose queue holds the timer
+    cpu: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerAction {
+    /// Wakes a task sleeping in `block_current`
+    Wake(Pid),
+    /// Ends the running task's time slice
+    Preempt,
+    /// Starts a new period for a control group that ran out of quota
+    Unthrottle(usize),
+}
+
+/// Per-CPU one-shot timers ordered by expiry, in timer ticks
+#[derive(Debug)]
+pub struct TimerQueue {
+    pending: BTreeMap<(u64, TimerId), TimerAction>,
+    /// Expiry of each pending timer, so they can be cancelled by id
+    expiries: BTreeMap<TimerId, u64>,
+}
+
+impl TimerQueue {
+    pub const fn new() -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            expiries: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, cpu: u64, expires: u64, action: TimerAction) -> TimerId {
+        let id = TimerId {
+            id: NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed),
+            cpu,
+        };
+        self.pending.insert((expires, id), action);
+        self.expiries.insert(id, expires);
+
+        id
+    }
+
+    /// Returns false when the timer already fired
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        match self.expiries.remove(&id) {
+            Some(expires) => self.pending.remove(&(expires, id)).is_some(),
+            None => false,
+        }
+    }
+
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.pending
+            .first_key_value()
+            .map(|((expires, _), _)| *expires)
+    }
+
+    /// Removes every timer due at `now` and returns their actions, oldest first
+    pub fn expire(&mut self, now: u64) -> Vec<TimerAction> {
+        let mut actions = Vec::new();
+
+        while let Some(entry) = self.pending.first_entry() {
+            let (expires, id) = *entry.key();
+
+            if expires > now {
+                break;
+            }
+
+            actions.push(entry.remove());
+            self.expiries.remove(&id);
+        }
+
+        actions
+    }
+}
+
+/// Arms a timer on the current CPU
+pub fn add_timer(expires: u64, action: TimerAction) -> TimerId {
+    let pcr = current_pcr();
+    pcr.sched.timers.lock().add(pcr.id, expires, action)
+}
+
+/// Returns false when the timer already fired
+pub fn cancel_timer(id: TimerId) -> bool {
+    get_pcr(id.cpu).sched.timers.lock().cancel(id)
+}
+
+/// Sleeps for at least `ms` milliseconds
+pub fn sleep(ms: u64) {
+    let deadline = now() + ms_to_ticks(ms);
+
+    while now() < deadline {
+        block_until(deadline);
+    }
+}
+
+/// Blocks the current task until it is woken or the tick `deadline` passes
+///
+/// Returns true when the deadline passed, the building block for sleeps and poll timeouts.
+pub fn block_until(deadline: u64) -> bool {
+    let Some(pid) = current_pcr().sched.current_pid else {
+        return true;
+    };
+    prepare_to_block();
+    let id = add_timer(deadline, TimerAction::Wake(pid));
+    block_current();
+
+    !cancel_timer(id)
+}
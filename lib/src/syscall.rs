@@ -7,6 +7,15 @@ pub const SYS_READ: usize = 63;
 pub const SYS_GETPID: usize = 39;
 pub const SYS_BRK: usize = 12;
 pub const SYS_KILL: usize = 62;
+pub const SYS_SETPGID: usize = 154;
+pub const SYS_GETPGID: usize = 155;
+pub const SYS_SETSID: usize = 157;
+pub const SYS_GETRANDOM: usize = 278;
+pub const SYS_SCHED_SETATTR: usize = 314;
+pub const SYS_SLEEP: usize = 101;
 pub const SYS_SPAWN: usize = 220;
 pub const SYS_CPU_REMOVE: usize = 221;
 pub const SYS_CPU_ADD: usize = 222;
+pub const SYS_FORK: usize = 223;
+pub const SYS_MMAP: usize = 224;
+pub const SYS_MUNMAP: usize = 215;
@@ -0,0 +1,250 @@
+//! Converts the scheduler trace the kernel prints on `dump` into Chrome `trace_event` JSON
+//!
+//! Usage: `sched-trace [serial.log] > trace.json`, reading standard input when no file is
+//! given. Lines that aren't part of the trace are ignored, so the whole serial output can be
+//! fed to it. The result opens in Perfetto or `chrome://tracing`, with one track per CPU.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs,
+    io::{self, Read},
+    process,
+};
+
+/// Idle tasks show up as gaps instead of slices
+const IDLE_NAME: &str = "idle";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    SwitchIn,
+    SwitchOut,
+    Wakeup,
+    Block,
+    Migrate,
+}
+
+impl Kind {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "switch-in" => Some(Kind::SwitchIn),
+            "switch-out" => Some(Kind::SwitchOut),
+            "wakeup" => Some(Kind::Wakeup),
+            "block" => Some(Kind::Block),
+            "migrate" => Some(Kind::Migrate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    cpu: u64,
+    time_us: u64,
+    kind: Kind,
+    pid: usize,
+    arg: u64,
+}
+
+#[derive(Debug, Default)]
+struct Trace {
+    names: BTreeMap<usize, String>,
+    events: Vec<Event>,
+}
+
+impl Trace {
+    fn parse(input: &str) -> Self {
+        let mut trace = Trace::default();
+
+        for line in input.lines() {
+            if let Some(rest) = after(line, "sched-trace-task ") {
+                if let Some((pid, name)) = rest.trim().split_once(' ') {
+                    if let Ok(pid) = pid.parse() {
+                        trace.names.insert(pid, name.to_string());
+                    }
+                }
+            } else if let Some(rest) = after(line, "sched-trace ") {
+                if let Some(event) = parse_event(rest) {
+                    trace.events.push(event);
+                }
+            }
+        }
+
+        // CPUs are dumped one after the other
+        trace.events.sort_by_key(|event| event.time_us);
+
+        trace
+    }
+
+    fn name(&self, pid: usize) -> String {
+        match self.names.get(&pid) {
+            Some(name) => format!("{} ({})", name, pid),
+            None => format!("pid {}", pid),
+        }
+    }
+
+    fn is_idle(&self, pid: usize) -> bool {
+        self.names.get(&pid).is_some_and(|name| name == IDLE_NAME)
+    }
+
+    fn to_json(&self) -> String {
+        let mut entries = Vec::new();
+        let mut cpus: BTreeMap<u64, Option<(usize, u64)>> = BTreeMap::new();
+        let end = self.events.last().map_or(0, |event| event.time_us);
+
+        for event in &self.events {
+            cpus.entry(event.cpu).or_default();
+        }
+
+        for &cpu in cpus.keys() {
+            entries.push(format!(
+                r#"{{"name":"thread_name","ph":"M","pid":0,"tid":{},"args":{{"name":"CPU {}"}}}}"#,
+                cpu, cpu
+            ));
+        }
+
+        for event in &self.events {
+            let running = cpus.get_mut(&event.cpu).unwrap();
+
+            match event.kind {
+                Kind::SwitchIn => {
+                    // a task that exited never switches out
+                    if let Some((pid, start)) = running.take() {
+                        entries.push(self.slice(event.cpu, pid, start, event.time_us));
+                    }
+                    *running = Some((event.pid, event.time_us));
+                }
+                Kind::SwitchOut => {
+                    if let Some((pid, start)) = *running {
+                        if pid == event.pid {
+                            entries.push(self.slice(event.cpu, pid, start, event.time_us));
+                            *running = None;
+                        }
+                    }
+                }
+                Kind::Wakeup => entries.push(self.instant(
+                    event.arg,
+                    event.time_us,
+                    &format!("wakeup {}", self.name(event.pid)),
+                    &format!(r#"{{"waker_cpu":{}}}"#, event.cpu),
+                )),
+                Kind::Block => entries.push(self.instant(
+                    event.cpu,
+                    event.time_us,
+                    &format!("block {}", self.name(event.pid)),
+                    "{}",
+                )),
+                Kind::Migrate => entries.push(self.instant(
+                    event.cpu,
+                    event.time_us,
+                    &format!("migrate {}", self.name(event.pid)),
+                    &format!(r#"{{"to_cpu":{}}}"#, event.arg),
+                )),
+            }
+        }
+
+        for (&cpu, running) in &cpus {
+            if let Some((pid, start)) = *running {
+                entries.push(self.slice(cpu, pid, start, end));
+            }
+        }
+
+        entries.retain(|entry| !entry.is_empty());
+
+        let mut out = String::from("{\"displayTimeUnit\":\"ms\",\"traceEvents\":[\n");
+        for (i, entry) in entries.iter().enumerate() {
+            let separator = if i + 1 == entries.len() { "" } else { "," };
+            writeln!(out, "{}{}", entry, separator).unwrap();
+        }
+        out.push_str("]}\n");
+
+        out
+    }
+
+    /// Complete event for the time `pid` ran on `cpu`, empty for the idle task
+    fn slice(&self, cpu: u64, pid: usize, start: u64, end: u64) -> String {
+        if self.is_idle(pid) {
+            return String::new();
+        }
+
+        format!(
+            r#"{{"name":"{}","ph":"X","pid":0,"tid":{},"ts":{},"dur":{},"args":{{"pid":{}}}}}"#,
+            escape(&self.name(pid)),
+            cpu,
+            start,
+            end - start,
+            pid
+        )
+    }
+
+    fn instant(&self, cpu: u64, time_us: u64, name: &str, args: &str) -> String {
+        format!(
+            r#"{{"name":"{}","ph":"i","s":"t","pid":0,"tid":{},"ts":{},"args":{}}}"#,
+            escape(name),
+            cpu,
+            time_us,
+            args
+        )
+    }
+}
+
+/// The rest of `line` after `marker`, the serial output may have a prefix before it
+fn after<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    line.find(marker).map(|start| &line[start + marker.len()..])
+}
+
+/// `<cpu> <time us> <event> <pid> <arg>`
+fn parse_event(fields: &str) -> Option<Event> {
+    let mut fields = fields.split_whitespace();
+
+    Some(Event {
+        cpu: fields.next()?.parse().ok()?,
+        time_us: fields.next()?.parse().ok()?,
+        kind: Kind::parse(fields.next()?)?,
+        pid: fields.next()?.parse().ok()?,
+        arg: fields.next()?.parse().ok()?,
+    })
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn main() {
+    // the serial output may have garbage in it, e.g. from a reset
+    let input = match env::args().nth(1) {
+        Some(path) => fs::read(&path).unwrap_or_else(|e| {
+            eprintln!("sched-trace: can't read {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => {
+            let mut input = Vec::new();
+            if let Err(e) = io::stdin().read_to_end(&mut input) {
+                eprintln!("sched-trace: can't read standard input: {}", e);
+                process::exit(1);
+            }
+            input
+        }
+    };
+
+    let trace = Trace::parse(&String::from_utf8_lossy(&input));
+
+    if trace.events.is_empty() {
+        eprintln!("sched-trace: no scheduler events found");
+        process::exit(1);
+    }
+
+    print!("{}", trace.to_json());
+}